@@ -1,16 +1,35 @@
 use shaku::module;
 use dotenv::dotenv;
+use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use raft_core::node::config::RaftConfig;
+use raft_core::node::progress::FlowControl;
+use raft_core::node::proposal::ProposalBatching;
+use raft_core::node::raft_node::RaftNode;
+use raft_core::node::read::ReadMode;
+use raft_core::node::snapshot::SnapshotPolicy;
+use raft_core::storage::file_storage::FileStorage;
+use raft_core::storage::wal::{DurabilityPolicy, WalOptions};
+
+use crate::domain::balance::balance_ledger::BalanceLedger;
 use crate::transport::router::{AppRouterImpl, AppRouterImplParameters};
 use crate::transport::app_router::{
     helloworld_router::HelloWorldRouterImpl,
-    balance_router::BalanceRouterImpl,
+    balance_router::{BalanceRouterImpl, BalanceRouterImplParameters},
+    cluster_router::{ClusterRouterImpl, ClusterRouterImplParameters},
+};
+use crate::transport::cluster_rpc::{
+    raft_rpc_inbound_network::{RaftRpcInboundNetwork, RaftRpcInboundNetworkParameters},
+    raft_rpc_outbound_network::RaftRpcOutboundNetwork,
 };
 
 module! {
     pub AppModule {
-        components = [AppRouterImpl, HelloWorldRouterImpl, BalanceRouterImpl],
+        components = [AppRouterImpl, HelloWorldRouterImpl, BalanceRouterImpl, ClusterRouterImpl, RaftRpcInboundNetwork],
         providers = [],
     }
 }
@@ -22,9 +41,189 @@ pub fn init_app_module() -> AppModule {
     // Read domain from environment variable with a default fallback
     let domain = env::var("SERVER_DOMAIN").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
 
+    // Cluster membership: this node's ID, the address other nodes reach it on,
+    // and the cluster RPC address of every other node
+    let server_id = env::var("SERVER_ID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(1);
+    let cluster_domain = env::var("CLUSTER_DOMAIN").unwrap_or_else(|_| "0.0.0.0:9090".to_string());
+    let cluster_peers = parse_cluster_peers(&env::var("CLUSTER_PEERS").unwrap_or_default());
+
+    // Directory holding this node's Raft term, vote and log
+    let data_dir = env::var("RAFT_DATA_DIR").unwrap_or_else(|_| format!("data/node-{}", server_id));
+    let wal_options = WalOptions {
+        durability: durability_policy_from_env(),
+        ..WalOptions::default()
+    };
+    let storage = FileStorage::open_with_options(&data_dir, wal_options)
+        .unwrap_or_else(|error| panic!("Failed to open Raft storage in {}: {}", data_dir, error));
+
+    let (initial_peers, initial_voter) = initial_voters_from_env(server_id, &cluster_peers);
+    let outbound_network = Arc::new(RaftRpcOutboundNetwork::new(server_id, cluster_peers));
+    let raft_node = RaftNode::new(
+        server_id,
+        initial_peers,
+        Box::new(BalanceLedger::default()),
+        Box::new(storage),
+        outbound_network,
+    )
+    .unwrap_or_else(|error| panic!("Failed to load Raft state from {}: {}", data_dir, error))
+    .with_config(raft_config_from_env())
+    .unwrap_or_else(|error| panic!("Invalid Raft configuration: {}", error))
+    // A node left out of the initial voters joins the cluster as a learner
+    .with_learner(!initial_voter)
+    .with_proposal_batching(proposal_batching_from_env());
+    let raft_node = Arc::new(raft_node);
+    raft_node.start();
+
     AppModule::builder()
         .with_component_parameters::<AppRouterImpl>(AppRouterImplParameters {
             domain,
         })
+        .with_component_parameters::<BalanceRouterImpl>(BalanceRouterImplParameters {
+            raft_node: raft_node.clone(),
+        })
+        .with_component_parameters::<ClusterRouterImpl>(ClusterRouterImplParameters {
+            raft_node: raft_node.clone(),
+        })
+        .with_component_parameters::<RaftRpcInboundNetwork>(RaftRpcInboundNetworkParameters {
+            domain: cluster_domain,
+            raft_node,
+        })
         .build()
 }
+
+/// Parses a peer list of the form `2=10.0.0.2:9090,3=10.0.0.3:9090`
+fn parse_cluster_peers(value: &str) -> HashMap<u64, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+        .filter_map(|peer| {
+            let parsed = peer
+                .split_once('=')
+                .and_then(|(id, address)| Some((id.trim().parse().ok()?, address.trim().to_string())));
+            if parsed.is_none() {
+                log::warn!("Ignoring malformed CLUSTER_PEERS entry '{}'", peer);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Reads the voters of the initial configuration from `CLUSTER_VOTERS`, a
+/// comma separated list of node IDs defaulting to this node and every
+/// `CLUSTER_PEERS` node
+///
+/// `CLUSTER_PEERS` may also list spare nodes that join later as learners. The
+/// initial configuration is ignored once the cluster configuration was changed.
+///
+/// # Returns
+/// The other initial voters, and whether this node is one of them
+fn initial_voters_from_env(server_id: u64, cluster_peers: &HashMap<u64, String>) -> (Vec<u64>, bool) {
+    let Ok(voters) = env::var("CLUSTER_VOTERS") else {
+        return (cluster_peers.keys().copied().collect(), true);
+    };
+    let voters: Vec<u64> = voters
+        .split(',')
+        .map(str::trim)
+        .filter(|voter| !voter.is_empty())
+        .filter_map(|voter| {
+            let parsed = voter.parse().ok();
+            if parsed.is_none() {
+                log::warn!("Ignoring malformed CLUSTER_VOTERS entry '{}'", voter);
+            }
+            parsed
+        })
+        .collect();
+    let initial_voter = voters.contains(&server_id);
+    (voters.into_iter().filter(|voter| *voter != server_id).collect(), initial_voter)
+}
+
+/// Reads the timers and limits of this node from the `RAFT_*` variables
+/// listed in `.env.example`, each defaulting to `RaftConfig::default()`
+///
+/// A snapshot threshold of 0 disables it.
+fn raft_config_from_env() -> RaftConfig {
+    let defaults = RaftConfig::default();
+    let millis = |name, default: Duration| Duration::from_millis(env_or(name, default.as_millis() as u64));
+    let threshold = |name, default: Option<u64>| Some(env_or(name, default.unwrap_or(0))).filter(|&value| value > 0);
+    RaftConfig {
+        election_timeout_min: millis("RAFT_ELECTION_TIMEOUT_MIN_MS", defaults.election_timeout_min),
+        election_timeout_max: millis("RAFT_ELECTION_TIMEOUT_MAX_MS", defaults.election_timeout_max),
+        heartbeat_interval: millis("RAFT_HEARTBEAT_INTERVAL_MS", defaults.heartbeat_interval),
+        max_entries_per_append: env_or("RAFT_MAX_ENTRIES_PER_APPEND", defaults.max_entries_per_append),
+        max_bytes_per_append: env_or("RAFT_MAX_BYTES_PER_APPEND", defaults.max_bytes_per_append),
+        flow_control: FlowControl {
+            max_in_flight_appends: env_or("RAFT_MAX_IN_FLIGHT_APPENDS", defaults.flow_control.max_in_flight_appends),
+            max_in_flight_bytes: env_or("RAFT_MAX_IN_FLIGHT_BYTES", defaults.flow_control.max_in_flight_bytes),
+        },
+        snapshot_policy: SnapshotPolicy {
+            max_applied_entries: threshold("RAFT_SNAPSHOT_MAX_APPLIED_ENTRIES", defaults.snapshot_policy.max_applied_entries),
+            max_log_bytes: threshold("RAFT_SNAPSHOT_MAX_LOG_BYTES", defaults.snapshot_policy.max_log_bytes),
+        },
+        pre_vote: env_or("RAFT_PRE_VOTE", defaults.pre_vote),
+        check_quorum: env_or("RAFT_CHECK_QUORUM", defaults.check_quorum),
+        read_mode: read_mode_from_env(),
+    }
+}
+
+/// Reads how concurrent proposals are coalesced into a single append from
+/// `RAFT_PROPOSAL_MAX_BATCH_ENTRIES`, `RAFT_PROPOSAL_MAX_BATCH_BYTES` and
+/// `RAFT_PROPOSAL_MAX_LINGER_US`, each defaulting to `ProposalBatching::default()`
+fn proposal_batching_from_env() -> ProposalBatching {
+    let defaults = ProposalBatching::default();
+    ProposalBatching {
+        max_batch_entries: env_or("RAFT_PROPOSAL_MAX_BATCH_ENTRIES", defaults.max_batch_entries),
+        max_batch_bytes: env_or("RAFT_PROPOSAL_MAX_BATCH_BYTES", defaults.max_batch_bytes),
+        max_linger: Duration::from_micros(env_or("RAFT_PROPOSAL_MAX_LINGER_US", defaults.max_linger.as_micros() as u64)),
+    }
+}
+
+/// Parses the environment variable `name`, or returns `default` if it is
+/// unset or malformed
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    let Ok(value) = env::var(name) else {
+        return default;
+    };
+    value.trim().parse().unwrap_or_else(|_| {
+        log::warn!("Ignoring malformed {} '{}'", name, value);
+        default
+    })
+}
+
+/// Reads when the Raft log is fsynced from `RAFT_FSYNC`: `every-append` (the
+/// default), `group-commit` or `none`
+///
+/// Group commit flushes once `RAFT_GROUP_COMMIT_MAX_ENTRIES` entries are waiting
+/// or `RAFT_GROUP_COMMIT_MAX_DELAY_US` microseconds have passed since the oldest
+/// of them was appended.
+fn durability_policy_from_env() -> DurabilityPolicy {
+    let policy = env::var("RAFT_FSYNC").unwrap_or_else(|_| "every-append".to_string());
+    match policy.as_str() {
+        "every-append" => DurabilityPolicy::EveryAppend,
+        "group-commit" => DurabilityPolicy::GroupCommit {
+            max_entries: env_or("RAFT_GROUP_COMMIT_MAX_ENTRIES", 64),
+            max_delay: Duration::from_micros(env_or("RAFT_GROUP_COMMIT_MAX_DELAY_US", 1000)),
+        },
+        "none" => DurabilityPolicy::None,
+        other => panic!("Invalid RAFT_FSYNC '{}', expected every-append, group-commit or none", other),
+    }
+}
+
+/// Reads how balance lookups confirm the leadership from `RAFT_READ_MODE`:
+/// `read-index` (the default) or `lease`
+///
+/// Lease reads trust the clocks of the nodes not to drift apart by more than
+/// `RAFT_LEASE_MAX_CLOCK_DRIFT_MS` milliseconds while a lease lasts.
+fn read_mode_from_env() -> ReadMode {
+    let mode = env::var("RAFT_READ_MODE").unwrap_or_else(|_| "read-index".to_string());
+    match mode.as_str() {
+        "read-index" => ReadMode::ReadIndex,
+        "lease" => ReadMode::Lease {
+            max_clock_drift: Duration::from_millis(env_or("RAFT_LEASE_MAX_CLOCK_DRIFT_MS", 10)),
+        },
+        other => panic!("Invalid RAFT_READ_MODE '{}', expected read-index or lease", other),
+    }
+}
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+/// Commands replicated through the Raft log and applied by the `BalanceLedger`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BalanceCommand {
+    CreateBalance {
+        uuid_most_significant: u64,
+        uuid_least_significant: u64,
+        user_id: u64,
+    },
+    Deposit {
+        balance_id: u64,
+        amount: u64,
+    },
+    Withdraw {
+        balance_id: u64,
+        amount: u64,
+    },
+}
+
+/// Read-only queries answered by the `BalanceLedger` without going through the Raft log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BalanceQuery {
+    GetBalance {
+        balance_id: u64,
+    },
+}
+
+/// Outcome of applying a `BalanceCommand`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BalanceEvent {
+    BalanceCreated {
+        uuid_most_significant: u64,
+        uuid_least_significant: u64,
+        balance_id: u64,
+    },
+    BalanceUpdated {
+        balance_id: u64,
+        amount: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+}
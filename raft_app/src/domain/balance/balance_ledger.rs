@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use raft_core::state_machine::StateMachine;
+
+use super::balance_command::{BalanceCommand, BalanceEvent, BalanceQuery};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Balance {
+    pub user_id: u64,
+    pub amount: u64,
+}
+
+/// In-memory ledger of balances, replicated as the Raft state machine
+///
+/// Commands and events are JSON encoded in the log entries and in the
+/// responses handed back to the proposer; queries and their answers (an
+/// optional `Balance`) are JSON encoded too, and snapshots are the JSON encoded
+/// ledger.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BalanceLedger {
+    balances: HashMap<u64, Balance>,
+    last_balance_id: u64,
+}
+
+impl BalanceLedger {
+    pub fn handle(&mut self, command: BalanceCommand) -> BalanceEvent {
+        match command {
+            BalanceCommand::CreateBalance { uuid_most_significant, uuid_least_significant, user_id } => {
+                self.last_balance_id += 1;
+                self.balances.insert(self.last_balance_id, Balance { user_id, amount: 0 });
+                BalanceEvent::BalanceCreated {
+                    uuid_most_significant,
+                    uuid_least_significant,
+                    balance_id: self.last_balance_id,
+                }
+            }
+            BalanceCommand::Deposit { balance_id, amount } => {
+                let Some(balance) = self.balances.get_mut(&balance_id) else {
+                    return rejected(format!("balance {} does not exist", balance_id));
+                };
+                let Some(new_amount) = balance.amount.checked_add(amount) else {
+                    return rejected(format!("balance {} would overflow", balance_id));
+                };
+                balance.amount = new_amount;
+                BalanceEvent::BalanceUpdated { balance_id, amount: new_amount }
+            }
+            BalanceCommand::Withdraw { balance_id, amount } => {
+                let Some(balance) = self.balances.get_mut(&balance_id) else {
+                    return rejected(format!("balance {} does not exist", balance_id));
+                };
+                let Some(new_amount) = balance.amount.checked_sub(amount) else {
+                    return rejected(format!("balance {} has insufficient funds", balance_id));
+                };
+                balance.amount = new_amount;
+                BalanceEvent::BalanceUpdated { balance_id, amount: new_amount }
+            }
+        }
+    }
+
+    pub fn query(&self, query: BalanceQuery) -> Option<&Balance> {
+        match query {
+            BalanceQuery::GetBalance { balance_id } => self.balances.get(&balance_id),
+        }
+    }
+}
+
+impl StateMachine for BalanceLedger {
+    fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8> {
+        let event = match serde_json::from_slice(command) {
+            Ok(command) => self.handle(command),
+            Err(error) => {
+                log::error!("Skipping malformed balance command at index {}: {}", index, error);
+                rejected(format!("malformed command: {}", error))
+            }
+        };
+        serde_json::to_vec(&event).unwrap()
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        let balance = match serde_json::from_slice(query) {
+            Ok(query) => BalanceLedger::query(self, query),
+            Err(error) => {
+                log::error!("Ignoring malformed balance query: {}", error);
+                None
+            }
+        };
+        serde_json::to_vec(&balance).unwrap()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        *self = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+}
+
+fn rejected(reason: String) -> BalanceEvent {
+    BalanceEvent::Rejected { reason }
+}
@@ -0,0 +1,2 @@
+pub mod balance_command;
+pub mod balance_ledger;
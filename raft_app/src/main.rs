@@ -3,13 +3,10 @@ pub mod domain;
 pub mod infrastructure;
 pub mod transport;
 
-use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
-
-use app_mod::{init_app_module, AppModule};
+use app_mod::init_app_module;
 use infrastructure::infra_bootstrap::InfraBootstrap;
-use raft_core::channel::{message::Message, request_reply_channel::{Consumer, RequestReplyChannel}};
 use shaku::HasComponent;
-use transport::{app_router::helloworld_payload::{MyRequest, MyResponse}, router::{AppRouterImpl, AppRouter, AppRouterImplParameters}};
+use transport::{cluster_rpc::raft_rpc_inbound_network::ClusterRpcServer, router::AppRouter};
 
 #[tokio::main]
 async fn main() {
@@ -17,7 +14,8 @@ async fn main() {
 
     let app_components = init_app_module();
     let app_router: &dyn AppRouter = app_components.resolve_ref();
-    app_router.start_router().await;
+    let cluster_rpc_server: &dyn ClusterRpcServer = app_components.resolve_ref();
+    tokio::join!(cluster_rpc_server.start_server(), app_router.start_router());
 
     
     // log::info!("Starting Raft application...");
@@ -1,6 +1,8 @@
 use raft_core::channel::payload::{Request, Response};
 
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct CreateBalance {
     pub uuid_most_significant: u64,
     pub uuid_least_significant: u64,
@@ -10,8 +12,6 @@ pub struct CreateBalance {
 
 impl Request for CreateBalance {}
 
-use serde::Serialize;
-
 #[derive(Debug, Clone, Serialize)]
 pub struct CreateBalanceResponse {
     pub uuid_most_significant: u64,
@@ -20,3 +20,26 @@ pub struct CreateBalanceResponse {
 }
 
 impl Response for CreateBalanceResponse {}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateBalance {
+    pub amount: u64,
+}
+
+impl Request for UpdateBalance {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceResponse {
+    pub balance_id: u64,
+    pub amount: u64,
+}
+
+impl Response for BalanceResponse {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorResponse {
+    pub message: String,
+    pub leader_id: Option<u64>, // set when the request must be retried on the leader
+}
+
+impl Response for ErrorResponse {}
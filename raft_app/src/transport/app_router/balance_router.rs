@@ -1,7 +1,12 @@
-use axum::{routing::post, response::IntoResponse, Router, Json};
+use std::sync::Arc;
+
+use axum::{extract::{Path, State}, http::StatusCode, routing::{get, post}, response::{IntoResponse, Response}, Router, Json};
+use raft_core::node::{proposal::ProposeError, raft_node::RaftNode, read::ReadError};
 use shaku::{Component, Interface};
 
-use super::balance_payload::CreateBalanceResponse;
+use super::balance_payload::{BalanceResponse, CreateBalance, CreateBalanceResponse, ErrorResponse, UpdateBalance};
+use crate::domain::balance::balance_command::{BalanceCommand, BalanceEvent, BalanceQuery};
+use crate::domain::balance::balance_ledger::Balance;
 
 pub trait BalanceRouter: Interface {
     fn create_router(&self) -> Router;
@@ -10,19 +15,121 @@ pub trait BalanceRouter: Interface {
 #[derive(Component)]
 #[shaku(interface = BalanceRouter)]
 pub struct BalanceRouterImpl {
+    raft_node: Arc<RaftNode>,
 }
 
 impl BalanceRouter for BalanceRouterImpl {
     fn create_router(&self) -> Router {
         Router::new()
             .route("/balance", post(create_balance))
+            .route("/balance/{balance_id}", get(get_balance))
+            .route("/balance/{balance_id}/deposit", post(deposit))
+            .route("/balance/{balance_id}/withdraw", post(withdraw))
+            .with_state(self.raft_node.clone())
+    }
+}
+
+async fn create_balance(State(raft_node): State<Arc<RaftNode>>, Json(request): Json<CreateBalance>) -> Response {
+    let command = BalanceCommand::CreateBalance {
+        uuid_most_significant: request.uuid_most_significant,
+        uuid_least_significant: request.uuid_least_significant,
+        user_id: request.user_id,
+    };
+    match propose(&raft_node, command).await {
+        Ok(BalanceEvent::BalanceCreated { uuid_most_significant, uuid_least_significant, balance_id }) => {
+            Json(CreateBalanceResponse {
+                uuid_most_significant,
+                uuid_least_significant,
+                balance_id,
+            }).into_response()
+        }
+        Ok(event) => unexpected_event(event),
+        Err(response) => response,
+    }
+}
+
+async fn get_balance(State(raft_node): State<Arc<RaftNode>>, Path(balance_id): Path<u64>) -> Response {
+    let query = serde_json::to_vec(&BalanceQuery::GetBalance { balance_id }).unwrap();
+    let output = match raft_node.read(query).await {
+        Ok(output) => output,
+        Err(error) => return read_error(error),
+    };
+    match serde_json::from_slice::<Option<Balance>>(&output).unwrap() {
+        Some(balance) => Json(BalanceResponse { balance_id, amount: balance.amount }).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, format!("balance {} does not exist", balance_id), None),
+    }
+}
+
+async fn deposit(
+    State(raft_node): State<Arc<RaftNode>>,
+    Path(balance_id): Path<u64>,
+    Json(request): Json<UpdateBalance>,
+) -> Response {
+    update_balance(&raft_node, BalanceCommand::Deposit { balance_id, amount: request.amount }).await
+}
+
+async fn withdraw(
+    State(raft_node): State<Arc<RaftNode>>,
+    Path(balance_id): Path<u64>,
+    Json(request): Json<UpdateBalance>,
+) -> Response {
+    update_balance(&raft_node, BalanceCommand::Withdraw { balance_id, amount: request.amount }).await
+}
+
+async fn update_balance(raft_node: &RaftNode, command: BalanceCommand) -> Response {
+    match propose(raft_node, command).await {
+        Ok(BalanceEvent::BalanceUpdated { balance_id, amount }) => {
+            Json(BalanceResponse { balance_id, amount }).into_response()
+        }
+        Ok(event) => unexpected_event(event),
+        Err(response) => response,
+    }
+}
+
+/// Replicates a command through Raft and waits for the ledger's outcome
+///
+/// Commands rejected by the ledger and proposals that did not complete are
+/// turned into error responses.
+async fn propose(raft_node: &RaftNode, command: BalanceCommand) -> Result<BalanceEvent, Response> {
+    let command = serde_json::to_vec(&command).unwrap();
+    let output = raft_node.propose(command).await.map_err(propose_error)?;
+    match serde_json::from_slice(&output).unwrap() {
+        BalanceEvent::Rejected { reason } => Err(error_response(StatusCode::UNPROCESSABLE_ENTITY, reason, None)),
+        event => Ok(event),
     }
 }
 
-async fn create_balance() -> impl IntoResponse {
-    Json(CreateBalanceResponse {
-        uuid_most_significant: 0,
-        uuid_least_significant: 0,
-        balance_id: 0,
-    })
-}
\ No newline at end of file
+fn propose_error(error: ProposeError) -> Response {
+    let status = match error {
+        ProposeError::NotLeader { .. } => StatusCode::MISDIRECTED_REQUEST,
+        ProposeError::Dropped | ProposeError::TransferringLeadership => StatusCode::SERVICE_UNAVAILABLE,
+        ProposeError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    };
+    let leader_id = match error {
+        ProposeError::NotLeader { leader_id } => leader_id,
+        _ => None,
+    };
+    error_response(status, error.to_string(), leader_id)
+}
+
+fn read_error(error: ReadError) -> Response {
+    let status = match error {
+        ReadError::NotLeader { .. } => StatusCode::MISDIRECTED_REQUEST,
+        ReadError::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+        ReadError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    };
+    let leader_id = match error {
+        ReadError::NotLeader { leader_id } => leader_id,
+        _ => None,
+    };
+    error_response(status, error.to_string(), leader_id)
+}
+
+fn unexpected_event(event: BalanceEvent) -> Response {
+    log::error!("Unexpected ledger event {:?}", event);
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "unexpected ledger event".to_string(), None)
+}
+
+fn error_response(status: StatusCode, message: String, leader_id: Option<u64>) -> Response {
+    (status, Json(ErrorResponse { message, leader_id })).into_response()
+}
@@ -0,0 +1,32 @@
+use raft_core::channel::payload::{Request, Response};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransferLeadership {
+    pub target_id: u64,
+}
+
+impl Request for TransferLeadership {}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddMember {
+    pub node_id: u64,
+}
+
+impl Request for AddMember {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaderResponse {
+    pub leader_id: Option<u64>,
+}
+
+impl Response for LeaderResponse {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MembersResponse {
+    pub voters: Vec<u64>,
+    pub learners: Vec<u64>,
+}
+
+impl Response for MembersResponse {}
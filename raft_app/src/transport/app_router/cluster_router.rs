@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, State}, http::StatusCode, routing::{delete, post}, response::{IntoResponse, Response}, Router, Json};
+use raft_core::node::{leadership::TransferError, membership::MembershipError, raft_node::RaftNode};
+use shaku::{Component, Interface};
+
+use super::balance_payload::ErrorResponse;
+use super::cluster_payload::{AddMember, LeaderResponse, MembersResponse, TransferLeadership};
+
+pub trait ClusterRouter: Interface {
+    fn create_router(&self) -> Router;
+}
+
+/// Cluster administration, e.g. moving the leadership away from a node before
+/// restarting it, or replacing a failed node
+#[derive(Component)]
+#[shaku(interface = ClusterRouter)]
+pub struct ClusterRouterImpl {
+    raft_node: Arc<RaftNode>,
+}
+
+impl ClusterRouter for ClusterRouterImpl {
+    fn create_router(&self) -> Router {
+        Router::new()
+            .route("/cluster/transfer-leadership", post(transfer_leadership))
+            .route("/cluster/voters", post(add_voter))
+            .route("/cluster/voters/{node_id}", delete(remove_voter))
+            .route("/cluster/learners", post(add_learner))
+            .route("/cluster/learners/{node_id}", delete(remove_learner))
+            .with_state(self.raft_node.clone())
+    }
+}
+
+async fn transfer_leadership(
+    State(raft_node): State<Arc<RaftNode>>,
+    Json(request): Json<TransferLeadership>,
+) -> Response {
+    match raft_node.transfer_leadership(request.target_id).await {
+        Ok(()) => Json(LeaderResponse { leader_id: raft_node.leader_id() }).into_response(),
+        Err(error) => {
+            let status = match error {
+                TransferError::NotLeader { .. } => StatusCode::MISDIRECTED_REQUEST,
+                TransferError::UnknownPeer { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                TransferError::MembershipChangeInProgress => StatusCode::CONFLICT,
+                TransferError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                TransferError::LeadershipLost { .. } => StatusCode::CONFLICT,
+            };
+            let leader_id = match error {
+                TransferError::NotLeader { leader_id } | TransferError::LeadershipLost { leader_id } => leader_id,
+                _ => None,
+            };
+            (status, Json(ErrorResponse { message: error.to_string(), leader_id })).into_response()
+        }
+    }
+}
+
+async fn add_voter(State(raft_node): State<Arc<RaftNode>>, Json(request): Json<AddMember>) -> Response {
+    let result = raft_node.add_voter(request.node_id).await;
+    membership_response(&raft_node, result)
+}
+
+async fn remove_voter(State(raft_node): State<Arc<RaftNode>>, Path(node_id): Path<u64>) -> Response {
+    let result = raft_node.remove_voter(node_id).await;
+    membership_response(&raft_node, result)
+}
+
+async fn add_learner(State(raft_node): State<Arc<RaftNode>>, Json(request): Json<AddMember>) -> Response {
+    let result = raft_node.add_learner(request.node_id).await;
+    membership_response(&raft_node, result)
+}
+
+async fn remove_learner(State(raft_node): State<Arc<RaftNode>>, Path(node_id): Path<u64>) -> Response {
+    let result = raft_node.remove_learner(node_id).await;
+    membership_response(&raft_node, result)
+}
+
+fn membership_response(raft_node: &RaftNode, result: Result<(), MembershipError>) -> Response {
+    match result {
+        Ok(()) => {
+            let configuration = raft_node.configuration();
+            let voters = configuration.voters.into_iter().collect();
+            let learners = configuration.learners.into_iter().collect();
+            Json(MembersResponse { voters, learners }).into_response()
+        }
+        Err(error) => {
+            let status = match error {
+                MembershipError::NotLeader { .. } => StatusCode::MISDIRECTED_REQUEST,
+                MembershipError::ChangeInProgress | MembershipError::LearnerCatchingUp { .. } => {
+                    StatusCode::CONFLICT
+                }
+                MembershipError::NoVoters => StatusCode::UNPROCESSABLE_ENTITY,
+                MembershipError::TransferringLeadership | MembershipError::LeadershipLost => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                MembershipError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            };
+            let leader_id = match error {
+                MembershipError::NotLeader { leader_id } => leader_id,
+                _ => None,
+            };
+            (status, Json(ErrorResponse { message: error.to_string(), leader_id })).into_response()
+        }
+    }
+}
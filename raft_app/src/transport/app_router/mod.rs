@@ -3,3 +3,6 @@ pub mod helloworld_payload;
 
 pub mod balance_payload;
 pub mod balance_router;
+
+pub mod cluster_payload;
+pub mod cluster_router;
@@ -1,2 +1,3 @@
 pub mod raft_rpc_outbound_network;
 pub mod raft_rpc_inbound_network;
+pub mod rpc_frame;
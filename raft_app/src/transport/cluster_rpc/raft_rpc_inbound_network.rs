@@ -1,13 +1,67 @@
 use std::error::Error;
+use std::sync::Arc;
 
+use async_trait::async_trait;
 use raft_core::network::{RpcMessage, ClusterInboundNetwork};
+use raft_core::node::raft_node::RaftNode;
+use shaku::{Component, Interface};
+use tokio::net::{TcpListener, TcpStream};
 
-pub struct RaftRpcInboundNetwork {}
+use super::rpc_frame::read_frame;
 
-#[async_trait::async_trait]
+#[async_trait]
+pub trait ClusterRpcServer: Interface {
+    async fn start_server(&self);
+}
+
+/// Accepts cluster RPC connections and hands every received message to the Raft node
+#[derive(Component)]
+#[shaku(interface = ClusterRpcServer)]
+pub struct RaftRpcInboundNetwork {
+    domain: String,
+    raft_node: Arc<RaftNode>,
+}
+
+#[async_trait]
 impl ClusterInboundNetwork for RaftRpcInboundNetwork {
     async fn receive(&self, message: RpcMessage, node_id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
-        log::info!("Received message from node {} with message {:?}", node_id, message);
-        todo!()
+        log::debug!("Received message from node {} with message {:?}", node_id, message);
+        self.raft_node.receive(message, node_id).await
+    }
+}
+
+#[async_trait]
+impl ClusterRpcServer for RaftRpcInboundNetwork {
+    async fn start_server(&self) {
+        let listener = TcpListener::bind(&self.domain).await.unwrap();
+        log::info!("Starting cluster rpc server on {}", &self.domain);
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    log::debug!("Accepted cluster connection from {}", address);
+                    tokio::spawn(handle_connection(stream, self.raft_node.clone()));
+                }
+                Err(error) => log::error!("Failed to accept cluster connection: {}", error),
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, inbound: Arc<dyn ClusterInboundNetwork>) {
+    if let Err(error) = stream.set_nodelay(true) {
+        log::warn!("Failed to disable Nagle on cluster connection: {}", error);
+    }
+    loop {
+        match read_frame(&mut stream).await {
+            Ok(envelope) => {
+                if let Err(error) = inbound.receive(envelope.message, envelope.from).await {
+                    log::error!("Failed to handle message from node {}: {}", envelope.from, error);
+                }
+            }
+            Err(error) => {
+                log::debug!("Cluster connection closed: {}", error);
+                break;
+            }
+        }
     }
 }
@@ -1,14 +1,51 @@
+use std::collections::HashMap;
 use std::error::Error;
 
 use raft_core::network::{RpcMessage, ClusterOutboundNetwork};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 
-pub struct RaftRpcOutboundNetwork {}
+use super::rpc_frame::{write_frame, RpcEnvelope};
+
+/// Sends RPCs to the other nodes over one lazily opened TCP connection per node
+///
+/// A connection that fails is dropped and re-opened on the next send.
+pub struct RaftRpcOutboundNetwork {
+    server_id: u64,
+    peers: HashMap<u64, String>,
+    connections: HashMap<u64, Mutex<Option<TcpStream>>>,
+}
+
+impl RaftRpcOutboundNetwork {
+    /// # Arguments
+    /// * `server_id` - ID of this node, sent along with every message
+    /// * `peers` - Cluster RPC address of every other node, by node ID
+    pub fn new(server_id: u64, peers: HashMap<u64, String>) -> Self {
+        let connections = peers.keys().map(|node_id| (*node_id, Mutex::new(None))).collect();
+        Self { server_id, peers, connections }
+    }
+}
 
 #[async_trait::async_trait]
 impl ClusterOutboundNetwork for RaftRpcOutboundNetwork {
     async fn send(&self, message: RpcMessage, node_id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
-        log::info!("Sending message to node {} with message {:?}", node_id, message);
-        todo!()
+        let (Some(address), Some(connection)) = (self.peers.get(&node_id), self.connections.get(&node_id)) else {
+            return Err(format!("unknown node {}", node_id).into());
+        };
+        log::debug!("Sending message to node {} with message {:?}", node_id, message);
+
+        let mut connection = connection.lock().await;
+        if connection.is_none() {
+            let stream = TcpStream::connect(address).await?;
+            stream.set_nodelay(true)?;
+            *connection = Some(stream);
+        }
+
+        let envelope = RpcEnvelope { from: self.server_id, message };
+        if let Err(error) = write_frame(connection.as_mut().unwrap(), &envelope).await {
+            *connection = None;
+            return Err(error.into());
+        }
+        Ok(())
     }
 }
-
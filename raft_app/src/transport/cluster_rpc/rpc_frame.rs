@@ -0,0 +1,42 @@
+use std::io::{Error, ErrorKind};
+
+use raft_core::network::RpcMessage;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound on the size of a single frame, to reject garbage length prefixes
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// An RPC message together with the ID of the node that sent it
+///
+/// Frames on the cluster connections are a 4-byte big-endian length prefix
+/// followed by the JSON encoding of an envelope.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcEnvelope {
+    pub from: u64,
+    pub message: RpcMessage,
+}
+
+pub async fn write_frame<W>(writer: &mut W, envelope: &RpcEnvelope) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let payload = serde_json::to_vec(envelope)?;
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await
+}
+
+pub async fn read_frame<R>(reader: &mut R) -> Result<RpcEnvelope, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = reader.read_u32().await? as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is too large", length)));
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(serde_json::from_slice(&payload)?)
+}
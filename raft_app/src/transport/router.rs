@@ -9,8 +9,9 @@ use hyper::{
 use tower_http::cors::CorsLayer;
 
 use super::app_router::{
-    balance_router::{self, BalanceRouter},
-    helloworld_router::{self, HelloWorldRouter}
+    balance_router::BalanceRouter,
+    cluster_router::ClusterRouter,
+    helloworld_router::HelloWorldRouter
 };
 use shaku::{Component, Interface};
 
@@ -29,6 +30,9 @@ pub struct AppRouterImpl {
 
     #[shaku(inject)]
     balance_router: Arc<dyn BalanceRouter>,
+
+    #[shaku(inject)]
+    cluster_router: Arc<dyn ClusterRouter>,
 }
 
 impl AppRouterImpl {
@@ -38,12 +42,11 @@ impl AppRouterImpl {
             .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
             .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
-        let router = Router::new()
+        Router::new()
             .layer(cors)
             .merge(self.hello_world_router.create_router())
-            .merge(self.balance_router.create_router());
-
-        router
+            .merge(self.balance_router.create_router())
+            .merge(self.cluster_router.create_router())
     }
 }
 
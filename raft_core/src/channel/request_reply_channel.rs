@@ -63,7 +63,7 @@ where
 
         if let Err(error) = self.sender.send(message).await {
             log::error!("Failed to send message: {error}");
-            return Err(Error::default());
+            return Err(Error);
         }
 
         match tokio::time::timeout(timeout, rx).await {
@@ -73,11 +73,11 @@ where
             },
             Ok(Err(_)) => {
                 log::error!("Producer: Message processed successfully but no response received!");
-                Err(Error::default())
+                Err(Error)
             },
             Err(_) => {
                 log::error!("Message processing timed out!");
-                Err(Error::default())
+                Err(Error)
             }
         }
     }
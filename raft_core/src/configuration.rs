@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+/// The set of servers whose votes count in elections and commitment
+///
+/// Membership changes go through joint consensus: the cluster first moves to a
+/// joint configuration C_old,new, in which every decision needs a majority of
+/// both the outgoing and the incoming voters, and then to C_new alone. A
+/// configuration is joint while `outgoing_voters` is not empty.
+///
+/// Learners receive every entry and snapshot like voters, but neither vote nor
+/// count towards a majority, so a new server can catch up without weakening
+/// the cluster's availability.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    pub voters: BTreeSet<u64>,            // Incoming voters, the only voters outside a transition
+    pub outgoing_voters: BTreeSet<u64>,   // Voters of C_old while in the joint configuration
+    #[serde(default)]
+    pub learners: BTreeSet<u64>,          // Non-voting members
+}
+
+impl Configuration {
+    /// Creates a simple (non-joint) configuration
+    pub fn new(voters: impl IntoIterator<Item = u64>) -> Self {
+        Self {
+            voters: voters.into_iter().collect(),
+            outgoing_voters: BTreeSet::new(),
+            learners: BTreeSet::new(),
+        }
+    }
+
+    /// Returns whether the cluster is transitioning between two configurations
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing_voters.is_empty()
+    }
+
+    /// Returns whether `server_id` votes in either configuration
+    pub fn is_voter(&self, server_id: u64) -> bool {
+        self.voters.contains(&server_id) || self.outgoing_voters.contains(&server_id)
+    }
+
+    /// Returns whether `server_id` is a learner
+    pub fn is_learner(&self, server_id: u64) -> bool {
+        self.learners.contains(&server_id)
+    }
+
+    /// Returns every server of both configurations, learners included
+    pub fn members(&self) -> BTreeSet<u64> {
+        let voters = self.voters.union(&self.outgoing_voters).copied();
+        voters.chain(self.learners.iter().copied()).collect()
+    }
+
+    /// Returns the joint configuration moving from this configuration to
+    /// `voters`; learners among `voters` are promoted
+    pub fn enter_joint(&self, voters: BTreeSet<u64>) -> Self {
+        Self {
+            learners: self.learners.difference(&voters).copied().collect(),
+            voters,
+            outgoing_voters: self.voters.clone(),
+        }
+    }
+
+    /// Returns the configuration that ends the transition, made of the incoming
+    /// voters and the learners
+    pub fn leave_joint(&self) -> Self {
+        Self {
+            voters: self.voters.clone(),
+            outgoing_voters: BTreeSet::new(),
+            learners: self.learners.clone(),
+        }
+    }
+
+    /// Returns whether the servers accepted by `granted` form a majority
+    ///
+    /// In a joint configuration both the incoming and the outgoing voters must
+    /// reach a majority.
+    pub fn has_majority(&self, granted: impl Fn(u64) -> bool) -> bool {
+        let majority = |voters: &BTreeSet<u64>| voters.iter().filter(|&&id| granted(id)).count() > voters.len() / 2;
+        majority(&self.voters) && (!self.is_joint() || majority(&self.outgoing_voters))
+    }
+
+    /// Returns the highest index acknowledged by a majority, given each voter's
+    /// acknowledged index
+    ///
+    /// In a joint configuration this is the lowest of the two majorities.
+    pub fn quorum_index(&self, acknowledged: impl Fn(u64) -> u64) -> u64 {
+        let quorum_index = |voters: &BTreeSet<u64>| {
+            let mut indexes: Vec<u64> = voters.iter().map(|&id| acknowledged(id)).collect();
+            indexes.sort_unstable_by(|a, b| b.cmp(a));
+            indexes.get(voters.len() / 2).copied().unwrap_or(0)
+        };
+        let index = quorum_index(&self.voters);
+        if self.is_joint() { index.min(quorum_index(&self.outgoing_voters)) } else { index }
+    }
+
+    /// Encodes the configuration as a log entry payload
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("configuration is always serializable")
+    }
+
+    /// Decodes a configuration from a log entry payload
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joint_majority_needs_both_configurations() {
+        let configuration = Configuration::new([1, 2, 3]).enter_joint([3, 4, 5].into());
+
+        assert!(!configuration.has_majority(|id| [1, 2, 3].contains(&id)));
+        assert!(!configuration.has_majority(|id| [3, 4, 5].contains(&id)));
+        assert!(configuration.has_majority(|id| [1, 3, 4].contains(&id)));
+        assert!(configuration.leave_joint().has_majority(|id| [3, 4].contains(&id)));
+    }
+
+    #[test]
+    fn learners_do_not_count_towards_a_majority() {
+        let mut configuration = Configuration::new([1, 2, 3]);
+        configuration.learners.insert(4);
+
+        assert_eq!(configuration.members(), [1, 2, 3, 4].into());
+        assert!(!configuration.has_majority(|id| [1, 4].contains(&id)));
+        assert_eq!(configuration.quorum_index(|id| [0, 5, 1, 1, 5][id as usize]), 1);
+
+        let promoted = configuration.enter_joint([1, 2, 3, 4].into()).leave_joint();
+        assert!(promoted.is_voter(4) && !promoted.is_learner(4));
+    }
+
+    #[test]
+    fn quorum_index_is_the_lowest_of_both_majorities() {
+        let configuration = Configuration::new([1, 2, 3]).enter_joint([1, 2, 3, 4].into());
+        let acknowledged = |id| [0, 9, 7, 3, 2][id as usize];
+
+        assert_eq!(configuration.quorum_index(acknowledged), 3);
+        assert_eq!(configuration.leave_joint().quorum_index(acknowledged), 3);
+        assert_eq!(Configuration::new([1, 2, 3]).quorum_index(acknowledged), 7);
+        assert_eq!(Configuration::default().quorum_index(acknowledged), 0);
+    }
+}
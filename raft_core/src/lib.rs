@@ -1,8 +1,12 @@
 pub mod channel;
+pub mod configuration;
 pub mod log;
+pub mod node;
 pub mod network;
 pub mod rpc;
 pub mod state;
+pub mod state_machine;
+pub mod storage;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -1,11 +1,64 @@
 //! Core log management module for Raft consensus implementation.
 //! Handles storage and manipulation of replicated log entries.
 
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::Configuration;
+
+/// What a log entry carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Command,         // Application command, applied to the state machine
+    Configuration,   // Cluster configuration, in effect as soon as it is appended
+    Noop,            // Empty entry a new leader appends to commit the entries of earlier terms
+}
+
 /// Represents a single log entry in the Raft log
-/// Each entry contains arbitrary bytes that represent commands to be replicated
-/// across the cluster. The actual interpretation of these bytes is left to the
-/// state machine implementation.
-pub type LogEntry = Vec<u8>;
+/// Every entry knows its own position, so entries can be replicated and stored
+/// without tracking which term or index they came from.
+/// Command entries contain arbitrary bytes that represent commands to be
+/// replicated across the cluster. The actual interpretation of these bytes is
+/// left to the state machine implementation. Configuration and no-op entries
+/// are handled by Raft itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,          // Term of the leader that created the entry
+    pub index: u64,         // Position of the entry in the log (1-based)
+    pub kind: EntryKind,
+    pub payload: Vec<u8>,   // Command bytes, the encoded configuration, or empty for a no-op
+}
+
+impl LogEntry {
+    /// Creates an entry of any kind at the given position
+    pub fn new(term: u64, index: u64, kind: EntryKind, payload: Vec<u8>) -> Self {
+        Self { term, index, kind, payload }
+    }
+
+    /// Creates an entry carrying an application command
+    pub fn command(term: u64, index: u64, payload: Vec<u8>) -> Self {
+        Self::new(term, index, EntryKind::Command, payload)
+    }
+
+    /// Creates an entry carrying a cluster configuration
+    pub fn configuration(term: u64, index: u64, configuration: &Configuration) -> Self {
+        Self::new(term, index, EntryKind::Configuration, configuration.encode())
+    }
+
+    /// Creates an empty entry
+    pub fn noop(term: u64, index: u64) -> Self {
+        Self::new(term, index, EntryKind::Noop, Vec::new())
+    }
+
+    /// Returns the size of the payload in bytes
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Returns whether the payload is empty
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+}
 
 /// LogPosition represents the unique identifier for a log entry in the Raft log.
 /// Each log entry is identified by both its term and index, which together
@@ -31,6 +84,8 @@ impl LogPosition {
     ///
     /// # Examples
     /// ```
+    /// use raft_core::log::LogPosition;
+    ///
     /// let pos = LogPosition::new(1, 1); // First entry in term 1
     /// ```
     pub fn new(term: u64, index: u64) -> Self {
@@ -48,36 +103,38 @@ pub struct SegmentLog {
     /// for maintaining log consistency and handling leader elections
     pub term: u64,
 
-    /// Vector of commands to be applied to the state machine
-    /// Index 0 corresponds to log index 1 (1-based indexing)
-    /// Each command is an opaque byte vector that will be interpreted
-    /// by the state machine
-    pub commands: Vec<LogEntry>,
+    /// Vector of consecutive entries, all of `term`
+    /// Element 0 is the entry at segment-relative index 1 (1-based indexing)
+    pub entries: Vec<LogEntry>,
 }
 
 impl SegmentLog {
-    /// Creates a new SegmentLog with the specified term and commands
+    /// Creates a new SegmentLog with the specified term and entries
     ///
     /// # Arguments
     /// * `term` - The term number for all entries in this segment
-    /// * `commands` - Vector of commands to be stored in this segment
+    /// * `entries` - Consecutive entries of `term` to be stored in this segment
     ///
     /// # Examples
     /// ```
-    /// let commands = vec![vec![1, 2, 3]]; // Single command
-    /// let segment = SegmentLog::new(1, commands);
+    /// use raft_core::log::{LogEntry, SegmentLog};
+    ///
+    /// let entries = vec![LogEntry::command(1, 1, vec![1, 2, 3])]; // Single command
+    /// let segment = SegmentLog::new(1, entries);
     /// ```
-    pub fn new(term: u64, commands: Vec<LogEntry>) -> Self {
-        Self { term, commands }
+    pub fn new(term: u64, entries: Vec<LogEntry>) -> Self {
+        debug_assert!(entries.iter().all(|entry| entry.term == term));
+        Self { term, entries }
     }
 
     /// Returns the index of the last entry in this segment
-    /// Since we use 1-based indexing, this is equal to the length of the commands vector
+    /// Since we use 1-based indexing, this is equal to the length of the entries vector
+    /// Indexes are relative to the segment; `RaftState` maps them to log indexes
     ///
     /// # Returns
     /// The index of the last entry, or 0 if the segment is empty
     pub fn last_log_index(&self) -> u64 {
-        self.commands.len() as u64
+        self.entries.len() as u64
     }
 
     /// Retrieves the log entry at the specified index
@@ -91,6 +148,6 @@ impl SegmentLog {
     ///
     /// Note: Index is 1-based, so we subtract 1 to access the internal vector
     pub fn log_at(&self, index: u64) -> Option<&LogEntry> {
-        self.commands.get(index as usize - 1)
+        self.entries.get(index as usize - 1)
     }
 }
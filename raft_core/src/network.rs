@@ -1,17 +1,51 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 
 use crate::rpc::{
-    AppendEntryRequest, AppendEntryResponse, RequestVoteRequest, RequestVoteResponse,
+    AppendEntryRequest, AppendEntryResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    PreVoteRequest, PreVoteResponse, ReadIndexRequest, ReadIndexResponse, RequestVoteRequest, RequestVoteResponse,
+    TimeoutNowRequest,
 };
 
 /// Represents different types of RPC messages in the Raft protocol
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum RpcMessage {
+    PreVote(PreVoteRequest),
+    PreVoteResponse(PreVoteResponse),
     RequestVote(RequestVoteRequest),
     RequestVoteResponse(RequestVoteResponse),
     AppendEntry(AppendEntryRequest),
     AppendEntryResponse(AppendEntryResponse),
+    InstallSnapshot(InstallSnapshotRequest),
+    InstallSnapshotResponse(InstallSnapshotResponse),
+    TimeoutNow(TimeoutNowRequest),
+    ReadIndex(ReadIndexRequest),
+    ReadIndexResponse(ReadIndexResponse),
+}
+
+impl RpcMessage {
+    /// Returns the term carried by the message
+    ///
+    /// Every Raft RPC carries the sender's current term, which the receiver uses
+    /// to detect stale leaders/candidates or to update its own term. The
+    /// exception is PreVote, which carries the term the sender has not started
+    /// yet and must not change the receiver's term.
+    pub fn term(&self) -> u64 {
+        match self {
+            RpcMessage::PreVote(request) => request.next_term,
+            RpcMessage::PreVoteResponse(response) => response.term,
+            RpcMessage::RequestVote(request) => request.current_term,
+            RpcMessage::RequestVoteResponse(response) => response.term,
+            RpcMessage::AppendEntry(request) => request.current_term,
+            RpcMessage::AppendEntryResponse(response) => response.term,
+            RpcMessage::InstallSnapshot(request) => request.current_term,
+            RpcMessage::InstallSnapshotResponse(response) => response.term,
+            RpcMessage::TimeoutNow(request) => request.current_term,
+            RpcMessage::ReadIndex(request) => request.current_term,
+            RpcMessage::ReadIndexResponse(response) => response.term,
+        }
+    }
 }
 
 /// Network communication trait for Raft nodes
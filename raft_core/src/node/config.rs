@@ -0,0 +1,172 @@
+//! Per-node tuning: timers, replication limits, snapshot thresholds, the read
+//! mode and the optional protocol extensions, checked together before a node
+//! uses them.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use tokio::time::Duration;
+
+use super::progress::FlowControl;
+use super::read::ReadMode;
+use super::snapshot::SnapshotPolicy;
+
+/// Timing and limits of a Raft server
+///
+/// Every server of a cluster should use the same timers: a follower whose
+/// election timeout is shorter than its leader's heartbeat interval keeps
+/// starting elections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftConfig {
+    pub election_timeout_min: Duration,   // Shortest randomized wait for a leader before campaigning
+    pub election_timeout_max: Duration,   // Longest randomized wait for a leader before campaigning
+    pub heartbeat_interval: Duration,     // Interval between two heartbeats sent by a leader
+    pub max_entries_per_append: usize,    // Entries carried by a single AppendEntry
+    pub max_bytes_per_append: u64,        // Payload bytes carried by a single AppendEntry, past its first entry
+    pub flow_control: FlowControl,        // AppendEntry in flight to each follower
+    pub snapshot_policy: SnapshotPolicy,  // When the log is compacted into a snapshot
+    pub pre_vote: bool,                   // Whether elections are preceded by a PreVote round
+    pub check_quorum: bool,               // Whether leaders step down without contact with a majority
+    pub read_mode: ReadMode,              // How reads confirm this server still leads the cluster
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout_min: Duration::from_millis(150),
+            election_timeout_max: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            max_entries_per_append: 64,
+            max_bytes_per_append: 1024 * 1024,
+            flow_control: FlowControl::default(),
+            snapshot_policy: SnapshotPolicy::default(),
+            pre_vote: false,
+            check_quorum: true,
+            read_mode: ReadMode::default(),
+        }
+    }
+}
+
+/// Reasons a `RaftConfig` is refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The election timeout range is empty or starts at zero
+    InvalidElectionTimeout { min: Duration, max: Duration },
+    /// Followers would time out before hearing the leader's next heartbeat
+    HeartbeatTooSlow { heartbeat_interval: Duration, election_timeout_min: Duration },
+    /// A limit that must let at least one entry or message through is zero
+    ZeroLimit { name: &'static str },
+    /// Lease reads rely on CheckQuorum, which is disabled
+    LeaseWithoutCheckQuorum,
+    /// The clock drift allowed for lease reads leaves no lease at all
+    ClockDriftTooLarge { max_clock_drift: Duration, election_timeout_min: Duration },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidElectionTimeout { min, max } => {
+                write!(f, "invalid election timeout range {:?}..={:?}", min, max)
+            }
+            ConfigError::HeartbeatTooSlow { heartbeat_interval, election_timeout_min } => write!(
+                f,
+                "heartbeat interval {:?} is not below the election timeout {:?}",
+                heartbeat_interval, election_timeout_min
+            ),
+            ConfigError::ZeroLimit { name } => write!(f, "{} must be at least 1", name),
+            ConfigError::LeaseWithoutCheckQuorum => write!(f, "lease reads require CheckQuorum"),
+            ConfigError::ClockDriftTooLarge { max_clock_drift, election_timeout_min } => write!(
+                f,
+                "maximum clock drift {:?} is not below the election timeout {:?}",
+                max_clock_drift, election_timeout_min
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl RaftConfig {
+    /// Checks that the settings can work together
+    ///
+    /// # Returns
+    /// * `Err(ConfigError::InvalidElectionTimeout)` - The election timeout
+    ///   range is empty or starts at zero
+    /// * `Err(ConfigError::HeartbeatTooSlow)` - The heartbeat interval is zero
+    ///   or not shorter than the shortest election timeout
+    /// * `Err(ConfigError::ZeroLimit)` - An append or in-flight limit is zero
+    /// * `Err(ConfigError::LeaseWithoutCheckQuorum)` - Lease reads are enabled
+    ///   without CheckQuorum
+    /// * `Err(ConfigError::ClockDriftTooLarge)` - The clock drift allowed for
+    ///   lease reads is not shorter than the shortest election timeout
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.election_timeout_min.is_zero() || self.election_timeout_min > self.election_timeout_max {
+            return Err(ConfigError::InvalidElectionTimeout {
+                min: self.election_timeout_min,
+                max: self.election_timeout_max,
+            });
+        }
+        if self.heartbeat_interval.is_zero() || self.heartbeat_interval >= self.election_timeout_min {
+            return Err(ConfigError::HeartbeatTooSlow {
+                heartbeat_interval: self.heartbeat_interval,
+                election_timeout_min: self.election_timeout_min,
+            });
+        }
+        let limits = [
+            ("max_entries_per_append", self.max_entries_per_append as u64),
+            ("max_bytes_per_append", self.max_bytes_per_append),
+            ("max_in_flight_appends", self.flow_control.max_in_flight_appends as u64),
+            ("max_in_flight_bytes", self.flow_control.max_in_flight_bytes),
+        ];
+        if let Some((name, _)) = limits.into_iter().find(|(_, limit)| *limit == 0) {
+            return Err(ConfigError::ZeroLimit { name });
+        }
+        if let ReadMode::Lease { max_clock_drift } = self.read_mode {
+            if !self.check_quorum {
+                return Err(ConfigError::LeaseWithoutCheckQuorum);
+            }
+            if max_clock_drift >= self.election_timeout_min {
+                return Err(ConfigError::ClockDriftTooLarge {
+                    max_clock_drift,
+                    election_timeout_min: self.election_timeout_min,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(RaftConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn unsafe_combinations_are_refused() {
+        let config = RaftConfig {
+            election_timeout_min: Duration::from_millis(300),
+            election_timeout_max: Duration::from_millis(150),
+            ..RaftConfig::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidElectionTimeout { .. })));
+
+        let config = RaftConfig { heartbeat_interval: Duration::from_millis(150), ..RaftConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::HeartbeatTooSlow { .. })));
+
+        let mut config = RaftConfig::default();
+        config.flow_control.max_in_flight_appends = 0;
+        assert_eq!(config.validate(), Err(ConfigError::ZeroLimit { name: "max_in_flight_appends" }));
+
+        let lease = ReadMode::Lease { max_clock_drift: Duration::from_millis(10) };
+        let config = RaftConfig { read_mode: lease, check_quorum: false, ..RaftConfig::default() };
+        assert_eq!(config.validate(), Err(ConfigError::LeaseWithoutCheckQuorum));
+
+        let lease = ReadMode::Lease { max_clock_drift: Duration::from_millis(150) };
+        let config = RaftConfig { read_mode: lease, ..RaftConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::ClockDriftTooLarge { .. })));
+    }
+}
@@ -0,0 +1,208 @@
+//! Leadership transfer: the leader brings a chosen follower up to date, then
+//! tells it to start an election right away with TimeoutNow.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use tokio::time::Instant;
+
+use super::node_core::NodeCore;
+use crate::network::RpcMessage;
+use crate::rpc::TimeoutNowRequest;
+use crate::state::State;
+
+/// Reasons a leadership transfer did not complete
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// This server is not the leader; `leader_id` is the current leader, if known
+    NotLeader { leader_id: Option<u64> },
+    /// The target is not a voter of the cluster; learners cannot lead
+    UnknownPeer { node_id: u64 },
+    /// A membership change has not completed yet; the transfer can be
+    /// requested again once it has
+    MembershipChangeInProgress,
+    /// The target did not take over within an election timeout; this server
+    /// stays the leader and accepts proposals again
+    Timeout,
+    /// This server stopped leading during the transfer, but the target did not
+    /// take over; `leader_id` is the current leader, if known
+    LeadershipLost { leader_id: Option<u64> },
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::NotLeader { leader_id: Some(leader_id) } => {
+                write!(f, "not the leader, the leader is node {}", leader_id)
+            }
+            TransferError::NotLeader { leader_id: None } => write!(f, "not the leader, the leader is unknown"),
+            TransferError::UnknownPeer { node_id } => write!(f, "node {} is not a voter of the cluster", node_id),
+            TransferError::MembershipChangeInProgress => write!(f, "a membership change is in progress"),
+            TransferError::Timeout => write!(f, "leadership transfer timed out"),
+            TransferError::LeadershipLost { leader_id: Some(leader_id) } => {
+                write!(f, "leadership was lost to node {} instead of the target", leader_id)
+            }
+            TransferError::LeadershipLost { leader_id: None } => {
+                write!(f, "leadership was lost and the target did not take over")
+            }
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+/// A leadership transfer in progress on the leader
+#[derive(Debug, Clone)]
+pub(crate) struct LeadershipTransfer {
+    pub(crate) target: u64,         // Server taking over
+    pub(crate) deadline: Instant,   // When the transfer is abandoned
+}
+
+impl NodeCore {
+    /// Starts handing the leadership over to `target`
+    ///
+    /// Proposals are refused until the transfer completes or is abandoned. The
+    /// target is sent TimeoutNow as soon as its log matches ours; until then,
+    /// regular replication brings it up to date. A membership change must
+    /// complete first, since a transfer would keep C_new from being appended.
+    pub(crate) fn transfer_leadership(&mut self, target: u64) -> Result<(), TransferError> {
+        if self.state.state != State::Leader {
+            return Err(TransferError::NotLeader { leader_id: self.leader_id });
+        }
+        if self.membership_change.is_some() || self.configuration.is_joint() {
+            return Err(TransferError::MembershipChangeInProgress);
+        }
+        let Some(progress) = self.progress.get(&target).filter(|_| self.configuration.is_voter(target)) else {
+            return Err(TransferError::UnknownPeer { node_id: target });
+        };
+        let (caught_up, paused) = (progress.match_index == self.state.last_log_index(), progress.is_paused(&self.config.flow_control));
+
+        log::info!(
+            "Server {} transfers leadership to {} in term {}",
+            self.state.server_id,
+            target,
+            self.state.current_term
+        );
+        // Voters stop honoring our lease once the target campaigns
+        self.lease_expiry = None;
+        self.leadership_transfer = Some(LeadershipTransfer {
+            target,
+            deadline: Instant::now() + self.config.election_timeout_max,
+        });
+        if caught_up {
+            self.send_timeout_now(target);
+        } else if !paused {
+            self.send_append(target);
+        }
+        Ok(())
+    }
+
+    /// Sends TimeoutNow to the transfer target once its log caught up with ours
+    pub(crate) fn continue_leadership_transfer(&mut self, peer: u64) {
+        if self.leadership_transfer.as_ref().is_some_and(|transfer| transfer.target == peer)
+            && self.progress.get(&peer).is_some_and(|progress| progress.match_index == self.state.last_log_index())
+        {
+            self.send_timeout_now(peer);
+        }
+    }
+
+    /// Abandons the leadership transfer if the target did not take over in time
+    ///
+    /// A committed joint configuration whose C_new could not be appended
+    /// during the transfer is left right away.
+    pub(crate) fn check_leadership_transfer(&mut self, now: Instant) {
+        if self.leadership_transfer.as_ref().is_some_and(|transfer| now >= transfer.deadline) {
+            let transfer = self.leadership_transfer.take().unwrap();
+            log::warn!(
+                "Server {} abandons leadership transfer to {} in term {}",
+                self.state.server_id,
+                transfer.target,
+                self.state.current_term
+            );
+            if self.configuration.is_joint() && self.configuration_index <= self.state.last_applied.index {
+                self.on_configuration_applied(self.configuration_index);
+            }
+        }
+    }
+
+    /// Starts an election right away if the leader of our term asks us to take over
+    ///
+    /// The election skips PreVote and its vote requests are flagged, so that
+    /// voters still hearing from the current leader do not ignore them.
+    pub(crate) fn handle_timeout_now(&mut self, request: TimeoutNowRequest) {
+        if request.current_term != self.state.current_term
+            || self.state.state == State::Leader
+            || !self.configuration.is_voter(self.state.server_id)
+        {
+            return;
+        }
+        log::info!(
+            "Server {} takes over leadership from {} after term {}",
+            self.state.server_id,
+            request.leader_id,
+            request.current_term
+        );
+        self.campaign(true);
+    }
+
+    fn send_timeout_now(&mut self, target: u64) {
+        let request = TimeoutNowRequest {
+            current_term: self.state.current_term,
+            leader_id: self.state.server_id,
+        };
+        self.send(target, RpcMessage::TimeoutNow(request));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::node_core::tests::{elected_leader, new_core};
+    use crate::rpc::AppendEntryResponse;
+
+    #[test]
+    fn timeout_now_is_sent_once_the_target_caught_up() {
+        let mut core = elected_leader(vec![2, 3]);
+        core.append_command(vec![1]);
+        core.take_outbox();
+
+        core.transfer_leadership(2).unwrap();
+        assert!(core.take_outbox().iter().all(|(_, message)| !matches!(message, RpcMessage::TimeoutNow(_))));
+
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 2, read_id: 0, ..Default::default() }));
+        let outbox = core.take_outbox();
+        assert!(matches!(outbox.as_slice(), [(2, RpcMessage::TimeoutNow(request))] if request.current_term == 1));
+        assert!(core.append_command(vec![2]).is_none());
+    }
+
+    #[test]
+    fn target_campaigns_immediately_on_timeout_now() {
+        let mut core = new_core(2, vec![1, 3]);
+        core.config.pre_vote = true;
+        core.state.current_term = 1;
+
+        core.step(1, RpcMessage::TimeoutNow(TimeoutNowRequest { current_term: 1, leader_id: 1 }));
+
+        assert_eq!(core.state.state, State::Candidate);
+        assert_eq!(core.state.current_term, 2);
+        for (_, message) in core.take_outbox() {
+            match message {
+                RpcMessage::RequestVote(request) => assert!(request.leadership_transfer),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn abandoned_transfer_accepts_proposals_again() {
+        let mut core = elected_leader(vec![2, 3]);
+        core.transfer_leadership(3).unwrap();
+        assert!(core.append_command(vec![1]).is_none());
+
+        core.leadership_transfer.as_mut().unwrap().deadline = Instant::now();
+        core.tick();
+
+        assert_eq!(core.state.state, State::Leader);
+        assert!(core.append_command(vec![1]).is_some());
+    }
+}
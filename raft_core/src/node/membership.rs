@@ -0,0 +1,459 @@
+//! Membership changes through joint consensus: the leader appends the joint
+//! configuration C_old,new, in which decisions need a majority of both the old
+//! and the new voters, then appends C_new once C_old,new is committed. Adding
+//! or removing a learner leaves every majority as it is and takes a single
+//! configuration entry.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use super::node_core::NodeCore;
+use super::progress::Progress;
+use crate::configuration::Configuration;
+use crate::log::{EntryKind, LogEntry};
+use crate::state::State;
+
+/// Reasons a membership change did not complete
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipError {
+    /// This server is not the leader; `leader_id` is the current leader, if known
+    NotLeader { leader_id: Option<u64> },
+    /// Another membership change has not completed yet; the change can be
+    /// requested again once it has
+    ChangeInProgress,
+    /// The change would leave the cluster without voters
+    NoVoters,
+    /// The learner to promote has not replicated the committed entries yet; it
+    /// can be promoted once it has
+    LearnerCatchingUp { node_id: u64 },
+    /// This server is handing its leadership over; the change can be requested
+    /// again once the new leader is known
+    TransferringLeadership,
+    /// This server lost its leadership before the change completed; the new
+    /// leader may still complete it
+    LeadershipLost,
+    /// The change did not complete in time; it may still complete
+    Timeout,
+}
+
+impl Display for MembershipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MembershipError::NotLeader { leader_id: Some(leader_id) } => {
+                write!(f, "not the leader, the leader is node {}", leader_id)
+            }
+            MembershipError::NotLeader { leader_id: None } => write!(f, "not the leader, the leader is unknown"),
+            MembershipError::ChangeInProgress => write!(f, "another membership change is in progress"),
+            MembershipError::NoVoters => write!(f, "the cluster needs at least one voter"),
+            MembershipError::LearnerCatchingUp { node_id } => {
+                write!(f, "learner {} has not caught up with the leader yet", node_id)
+            }
+            MembershipError::TransferringLeadership => write!(f, "leader is transferring its leadership"),
+            MembershipError::LeadershipLost => write!(f, "leadership lost before the change completed"),
+            MembershipError::Timeout => write!(f, "membership change timed out"),
+        }
+    }
+}
+
+impl Error for MembershipError {}
+
+/// A change to the members of the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MembershipChange {
+    AddVoter(u64),   // Adds a new server as a voter, or promotes a learner
+    RemoveVoter(u64),
+    AddLearner(u64),
+    RemoveLearner(u64),
+}
+
+/// Why a configuration entry of our log is known to decode
+pub(crate) const CHECKED_CONFIGURATION: &str = "configuration entries are checked before they reach the log";
+
+/// Decodes the configuration carried by a configuration entry, `None` if the
+/// payload is malformed
+///
+/// Entries from another server are checked before they reach the log (see
+/// `handle_append_entry`), so a configuration entry of our log always decodes.
+pub(crate) fn decode_configuration(entry: &LogEntry) -> Option<Configuration> {
+    Configuration::decode(&entry.payload)
+}
+
+impl NodeCore {
+    /// Starts changing the members of the cluster; `response_channel` is
+    /// answered once the new configuration is committed
+    ///
+    /// Only one change is in progress at a time. A change that leaves the
+    /// members as they are completes right away.
+    pub(crate) fn change_membership(
+        &mut self,
+        change: MembershipChange,
+        response_channel: oneshot::Sender<Result<(), MembershipError>>,
+    ) {
+        match self.append_configuration(change) {
+            Ok(true) => self.membership_change = Some(response_channel),
+            Ok(false) => {
+                let _ = response_channel.send(Ok(()));
+            }
+            Err(error) => {
+                let _ = response_channel.send(Err(error));
+            }
+        }
+    }
+
+    /// Appends the configuration applying `change`: the joint configuration
+    /// if the voters change, the new configuration otherwise
+    ///
+    /// # Returns
+    /// * `Ok(true)` - A configuration was appended
+    /// * `Ok(false)` - The members already match the change
+    fn append_configuration(&mut self, change: MembershipChange) -> Result<bool, MembershipError> {
+        if self.state.state != State::Leader {
+            return Err(MembershipError::NotLeader { leader_id: self.leader_id });
+        }
+        if self.leadership_transfer.is_some() {
+            return Err(MembershipError::TransferringLeadership);
+        }
+        // The latest configuration must be committed before it is changed again
+        if self.membership_change.is_some()
+            || self.configuration.is_joint()
+            || self.configuration_index > self.state.commit_position.index
+        {
+            return Err(MembershipError::ChangeInProgress);
+        }
+
+        let mut voters = self.configuration.voters.clone();
+        let mut configuration = self.configuration.clone();
+        match change {
+            MembershipChange::AddVoter(node_id) => {
+                if self.configuration.is_learner(node_id) && !self.caught_up(node_id) {
+                    return Err(MembershipError::LearnerCatchingUp { node_id });
+                }
+                voters.insert(node_id);
+            }
+            MembershipChange::RemoveVoter(node_id) => {
+                voters.remove(&node_id);
+            }
+            // A voter already receives every entry
+            MembershipChange::AddLearner(node_id) if !self.configuration.is_voter(node_id) => {
+                configuration.learners.insert(node_id);
+            }
+            MembershipChange::AddLearner(_) => {}
+            MembershipChange::RemoveLearner(node_id) => {
+                configuration.learners.remove(&node_id);
+            }
+        }
+        if voters.is_empty() {
+            return Err(MembershipError::NoVoters);
+        }
+        if voters != configuration.voters {
+            configuration = configuration.enter_joint(voters);
+            log::info!(
+                "Server {} starts moving voters from {:?} to {:?} in term {}",
+                self.state.server_id,
+                configuration.outgoing_voters,
+                configuration.voters,
+                self.state.current_term
+            );
+        } else if configuration == self.configuration {
+            return Ok(false);
+        }
+        self.append_entry(EntryKind::Configuration, configuration.encode());
+        Ok(true)
+    }
+
+    /// Whether `node_id` stores every committed entry
+    fn caught_up(&self, node_id: u64) -> bool {
+        self.progress
+            .get(&node_id)
+            .is_some_and(|progress| progress.match_index >= self.state.commit_position.index)
+    }
+
+    /// Moves the membership change forward once the configuration entry at
+    /// `index` is applied, hence committed
+    ///
+    /// The leader appends C_new once C_old,new is committed. Once C_new is
+    /// committed the change is complete, and a leader that is not one of its
+    /// voters steps down.
+    pub(crate) fn on_configuration_applied(&mut self, index: u64) {
+        // A later configuration was appended meanwhile
+        if self.state.state != State::Leader || index != self.configuration_index {
+            return;
+        }
+        if self.configuration.is_joint() {
+            let configuration = self.configuration.leave_joint();
+            self.append_entry(EntryKind::Configuration, configuration.encode());
+            return;
+        }
+
+        if let Some(client) = self.membership_change.take() {
+            let _ = client.send(Ok(()));
+        }
+        if !self.configuration.is_voter(self.state.server_id) {
+            log::info!(
+                "Server {} is no longer a voter, stepping down in term {}",
+                self.state.server_id,
+                self.state.current_term
+            );
+            self.become_follower(self.state.current_term, None);
+        }
+    }
+
+    /// Makes this server a learner of the initial configuration, unless the log
+    /// or the snapshot already holds a configuration
+    pub(crate) fn start_as_learner(&mut self) {
+        if self.state.snapshot_position.index > 0 || self.configuration_index > 0 {
+            return;
+        }
+        let server_id = self.state.server_id;
+        self.applied_configuration.voters.remove(&server_id);
+        self.applied_configuration.learners.insert(server_id);
+        self.refresh_configuration();
+    }
+
+    /// Adopts the latest configuration entry of the log, or the configuration
+    /// as of `last_applied` if the log holds none
+    pub(crate) fn refresh_configuration(&mut self) {
+        let latest = (self.state.snapshot_position.index + 1..=self.state.last_log_index())
+            .rev()
+            .find_map(|index| {
+                let entry = self.state.entry_at(index)?;
+                (entry.kind == EntryKind::Configuration).then(|| (decode_configuration(entry).expect(CHECKED_CONFIGURATION), index))
+            });
+        let (configuration, index) = latest.unwrap_or_else(|| (self.applied_configuration.clone(), 0));
+        self.set_configuration(configuration, index);
+    }
+
+    /// Makes `configuration` the one in effect
+    ///
+    /// `peers` follows the members of the configuration; on the leader, peers
+    /// that joined are sent entries from the end of the log on.
+    pub(crate) fn set_configuration(&mut self, configuration: Configuration, index: u64) {
+        if configuration != self.configuration {
+            log::info!(
+                "Server {} uses configuration {:?} (outgoing {:?}, learners {:?}) from index {}",
+                self.state.server_id,
+                configuration.voters,
+                configuration.outgoing_voters,
+                configuration.learners,
+                index
+            );
+        }
+        let server_id = self.state.server_id;
+        let peers: Vec<u64> = configuration.members().into_iter().filter(|id| *id != server_id).collect();
+        if self.state.state == State::Leader && peers != self.peers {
+            self.update_progress(&peers);
+        }
+        self.peers = peers;
+        self.configuration = configuration;
+        self.configuration_index = index;
+    }
+
+    /// Tracks the progress of the peers that joined and forgets the ones that left
+    fn update_progress(&mut self, peers: &[u64]) {
+        let next_index = self.state.last_log_index() + 1;
+        // A new peer counts as reachable until it had a chance to answer
+        let now = Instant::now();
+        self.progress.retain(|peer, _| peers.contains(peer));
+        for &peer in peers {
+            self.progress.entry(peer).or_insert_with(|| Progress::new(next_index, Some(now)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::RpcMessage;
+    use crate::node::leadership::{LeadershipTransfer, TransferError};
+    use crate::node::node_core::tests::{elected_leader, new_core};
+    use crate::rpc::{AppendEntryRequest, AppendEntryResponse};
+    use crate::state_machine::tests::EchoStateMachine;
+    use crate::storage::memory_storage::MemoryStorage;
+
+    fn ack(core: &mut NodeCore, from: u64, match_index: u64) {
+        let response = AppendEntryResponse { term: core.state.current_term, success: true, match_index, read_id: 0, ..Default::default() };
+        core.step(from, RpcMessage::AppendEntryResponse(response));
+        core.apply_committed();
+    }
+
+    fn change(core: &mut NodeCore, change: MembershipChange) -> oneshot::Receiver<Result<(), MembershipError>> {
+        let (sender, receiver) = oneshot::channel();
+        core.change_membership(change, sender);
+        receiver
+    }
+
+    #[test]
+    fn adding_a_voter_commits_with_majorities_of_both_configurations() {
+        let mut core = elected_leader(vec![2, 3]);
+        let mut receiver = change(&mut core, MembershipChange::AddVoter(4));
+
+        assert!(core.configuration.is_joint());
+        assert_eq!(core.peers, vec![2, 3, 4]);
+        assert!(core.take_outbox().iter().any(|(to, _)| *to == 4));
+
+        // Servers 1 and 2 are a majority of C_old, but not of C_new
+        ack(&mut core, 2, 2);
+        assert_eq!(core.state.commit_position.index, 0);
+        ack(&mut core, 4, 2);
+        assert_eq!(core.state.commit_position.index, 2);
+
+        // C_new was appended once C_old,new committed
+        assert_eq!(core.configuration, Configuration::new([1, 2, 3, 4]));
+        assert_eq!(core.configuration_index, 3);
+        assert!(receiver.try_recv().is_err());
+        ack(&mut core, 3, 3);
+        ack(&mut core, 4, 3);
+        assert_eq!(receiver.try_recv().unwrap(), Ok(()));
+        assert_eq!(core.applied_configuration, Configuration::new([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn removed_leader_steps_down_once_the_new_configuration_commits() {
+        let mut core = elected_leader(vec![2, 3]);
+        let mut receiver = change(&mut core, MembershipChange::RemoveVoter(1));
+        ack(&mut core, 2, 2);
+        ack(&mut core, 3, 2);
+        assert_eq!(core.state.state, State::Leader);
+
+        ack(&mut core, 2, 3);
+        assert_eq!(core.state.state, State::Leader);
+        ack(&mut core, 3, 3);
+        assert_eq!(core.state.state, State::Follower);
+        assert_eq!(receiver.try_recv().unwrap(), Ok(()));
+        assert!(!core.configuration.is_voter(1));
+
+        // A server outside the configuration never campaigns
+        core.election_deadline = Instant::now();
+        core.tick();
+        assert_eq!(core.state.state, State::Follower);
+    }
+
+    #[test]
+    fn only_one_change_is_in_progress_at_a_time() {
+        let mut core = elected_leader(vec![2, 3]);
+        let _receiver = change(&mut core, MembershipChange::AddVoter(4));
+
+        let mut receiver = change(&mut core, MembershipChange::RemoveVoter(3));
+        assert_eq!(receiver.try_recv().unwrap(), Err(MembershipError::ChangeInProgress));
+        let mut receiver = change(&mut new_core(1, vec![2]), MembershipChange::AddVoter(3));
+        assert_eq!(receiver.try_recv().unwrap(), Err(MembershipError::NotLeader { leader_id: None }));
+    }
+
+    #[test]
+    fn leadership_is_not_transferred_during_a_change() {
+        let mut core = elected_leader(vec![2, 3]);
+        let _receiver = change(&mut core, MembershipChange::AddVoter(4));
+
+        assert_eq!(core.transfer_leadership(2), Err(TransferError::MembershipChangeInProgress));
+        assert!(core.leadership_transfer.is_none());
+    }
+
+    #[test]
+    fn abandoned_transfer_leaves_the_joint_configuration() {
+        let mut core = elected_leader(vec![2, 3]);
+        let mut receiver = change(&mut core, MembershipChange::AddVoter(4));
+        core.leadership_transfer = Some(LeadershipTransfer { target: 2, deadline: Instant::now() });
+
+        // C_new cannot be appended while the transfer is in progress...
+        ack(&mut core, 2, 2);
+        ack(&mut core, 4, 2);
+        assert_eq!(core.state.last_applied.index, 2);
+        assert!(core.configuration.is_joint());
+
+        // ...but is as soon as the transfer is abandoned
+        core.tick();
+        assert!(core.leadership_transfer.is_none());
+        assert_eq!(core.configuration, Configuration::new([1, 2, 3, 4]));
+        ack(&mut core, 2, 3);
+        ack(&mut core, 4, 3);
+        assert_eq!(receiver.try_recv().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn learner_receives_entries_without_counting_towards_commit() {
+        let mut core = elected_leader(vec![2, 3]);
+        let mut receiver = change(&mut core, MembershipChange::AddLearner(4));
+        assert!(!core.configuration.is_joint());
+        ack(&mut core, 2, 2);
+        assert_eq!(receiver.try_recv().unwrap(), Ok(()));
+
+        core.append_command(vec![1]);
+        assert!(core.take_outbox().iter().any(|(to, message)| *to == 4 && matches!(message, RpcMessage::AppendEntry(_))));
+        ack(&mut core, 4, 3);
+        assert_eq!(core.state.commit_position.index, 2);
+    }
+
+    #[test]
+    fn learner_is_promoted_once_it_caught_up() {
+        let mut core = elected_leader(vec![2, 3]);
+        let _receiver = change(&mut core, MembershipChange::AddLearner(4));
+        ack(&mut core, 2, 2);
+
+        let mut receiver = change(&mut core, MembershipChange::AddVoter(4));
+        assert_eq!(receiver.try_recv().unwrap(), Err(MembershipError::LearnerCatchingUp { node_id: 4 }));
+
+        ack(&mut core, 4, 2);
+        let _receiver = change(&mut core, MembershipChange::AddVoter(4));
+        assert!(core.configuration.is_joint());
+        assert!(core.configuration.is_voter(4) && !core.configuration.is_learner(4));
+    }
+
+    #[test]
+    fn learner_never_campaigns() {
+        let mut core = new_core(4, vec![1, 2, 3]);
+        core.start_as_learner();
+        assert_eq!(core.configuration.voters, [1, 2, 3].into());
+
+        core.election_deadline = Instant::now();
+        core.tick();
+        assert_eq!(core.state.state, State::Follower);
+        assert!(core.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn restarted_server_restores_the_configuration_from_its_snapshot_and_log() {
+        let mut core = new_core(1, vec![]);
+        core.state.current_term = 1;
+        core.become_leader();
+        let mut receiver = change(&mut core, MembershipChange::AddVoter(2));
+        ack(&mut core, 2, 2);
+        ack(&mut core, 2, 3);
+        assert_eq!(receiver.try_recv().unwrap(), Ok(()));
+        core.take_snapshot();
+        let _receiver = change(&mut core, MembershipChange::RemoveVoter(2));
+
+        let storage = std::mem::replace(&mut core.storage, Box::new(MemoryStorage::new()));
+        let restarted = NodeCore::new(1, vec![], Box::new(EchoStateMachine::default()), storage).unwrap();
+
+        assert_eq!(restarted.applied_configuration, Configuration::new([1, 2]));
+        assert_eq!(restarted.configuration, Configuration::new([1, 2]).enter_joint([1].into()));
+        assert_eq!(restarted.configuration_index, 4);
+        assert_eq!(restarted.peers, vec![2]);
+    }
+
+    #[test]
+    fn follower_reverts_to_the_previous_configuration_when_its_entry_is_removed() {
+        let mut core = new_core(2, vec![1, 3]);
+        let joint = Configuration::new([1, 2, 3]).enter_joint([1, 2, 3, 4].into());
+        let request = |term, entries| AppendEntryRequest {
+            current_term: term,
+            leader_id: 1,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            append_index: 1,
+            entries,
+            leader_commit: 0,
+            read_id: 0,
+        };
+        core.step(1, RpcMessage::AppendEntry(request(1, vec![LogEntry::configuration(1, 1, &joint)])));
+        assert_eq!(core.configuration, joint);
+        assert_eq!(core.peers, vec![1, 3, 4]);
+
+        core.step(1, RpcMessage::AppendEntry(request(2, vec![LogEntry::command(2, 1, vec![1])])));
+        assert_eq!(core.configuration, Configuration::new([1, 2, 3]));
+        assert_eq!(core.peers, vec![1, 3]);
+    }
+}
@@ -0,0 +1,11 @@
+pub mod config;
+pub mod leadership;
+pub mod membership;
+pub mod progress;
+pub mod proposal;
+pub mod raft_node;
+pub mod read;
+pub mod snapshot;
+
+mod node_core;
+mod replication;
@@ -0,0 +1,917 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use rand::Rng;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+use crate::configuration::Configuration;
+use crate::log::{EntryKind, LogEntry, LogPosition};
+use crate::network::RpcMessage;
+use crate::rpc::{
+    AppendEntryResponse, PreVoteRequest, PreVoteResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use crate::state::{RaftState, State};
+use crate::state_machine::StateMachine;
+use crate::storage::raft_storage::{HardState, PendingSync, RaftStorage, Snapshot, StorageError};
+
+use super::leadership::LeadershipTransfer;
+use super::membership::{CHECKED_CONFIGURATION, MembershipError, decode_configuration};
+use super::proposal::{ProposeError, ProposeResponse};
+use super::config::RaftConfig;
+use super::read::{ForwardedRead, PendingRead};
+use super::progress::Progress;
+use super::snapshot::SNAPSHOT_CHUNK_SIZE;
+
+/// Synchronous Raft state machine driven by `RaftNode`
+///
+/// NodeCore never performs I/O itself: inbound messages and timer ticks are fed
+/// in through `step` and `tick`, and every message that has to leave the node is
+/// queued in the outbox, which the driver drains and hands to the network.
+pub(crate) struct NodeCore {
+    pub(crate) state: RaftState,
+    pub(crate) peers: Vec<u64>,          // IDs of the other servers in `configuration`
+    pub(crate) leader_id: Option<u64>,   // Leader of the current term, if known
+    pub(crate) configuration: Configuration,           // Latest configuration in the log, in effect once appended
+    pub(crate) configuration_index: u64,               // Log index of `configuration`, 0 if it precedes the log
+    pub(crate) applied_configuration: Configuration,   // Configuration as of `last_applied`, saved with snapshots
+    pub(crate) state_machine: Box<dyn StateMachine>,
+    pub(crate) storage: Box<dyn RaftStorage>,
+    pub(crate) config: RaftConfig,
+
+    // Clients waiting for the result of a command proposed on this server,
+    // by log index, together with the term the command was appended in
+    pub(crate) pending_responses: HashMap<u64, (u64, oneshot::Sender<ProposeResponse>)>,
+    pub(crate) pending_reads: VecDeque<PendingRead>, // Reads waiting on the leader, oldest first
+    pub(crate) read_sequence: u64,                   // ID of the latest heartbeat round, echoed as `read_id`
+    pub(crate) round_starts: VecDeque<(u64, Instant)>, // When unconfirmed heartbeat rounds were sent (lease reads only)
+    pub(crate) lease_expiry: Option<Instant>,        // Until when a lease read needs no heartbeat round
+    pub(crate) forwarded_reads: HashMap<u64, ForwardedRead>, // Reads waiting for the leader's read index, by request ID
+    pub(crate) forwarded_read_sequence: u64,         // ID of the latest read forwarded to the leader
+
+    // Leader state, reinitialized after election
+    pub(crate) progress: BTreeMap<u64, Progress>, // Replication progress of every peer, by node ID
+    pub(crate) leadership_transfer: Option<LeadershipTransfer>, // Transfer in progress; proposals are refused meanwhile
+    // Client waiting for the membership change in progress to complete
+    pub(crate) membership_change: Option<oneshot::Sender<Result<(), MembershipError>>>,
+
+    // Successful AppendEntry reply waiting for its entries to become durable, with its target
+    pub(crate) held_append_response: Option<(u64, AppendEntryResponse)>,
+
+    pub(crate) incoming_snapshot: Option<Snapshot>, // Snapshot chunks received from the leader so far
+    pub(crate) snapshot_chunk_size: usize,          // Maximum snapshot bytes per InstallSnapshot
+
+    pub(crate) votes_granted: HashSet<u64>,           // Servers that (pre-)voted for us in the current election
+    pub(crate) last_leader_contact: Option<Instant>,  // When the leader of the current term was last heard from
+    pub(crate) election_deadline: Instant,  // When a follower/candidate starts a new election
+    pub(crate) heartbeat_deadline: Instant, // When a leader sends its next heartbeat
+    pub(crate) quorum_check_deadline: Instant, // When a leader next checks it still reaches a majority
+
+    pub(crate) outbox: Vec<(u64, RpcMessage)>, // Messages waiting to be sent, with their target node
+}
+
+impl NodeCore {
+    /// Creates a new NodeCore in the Follower state
+    ///
+    /// The term, vote, snapshot and log are restored from `storage`, so a
+    /// restarted server resumes with the persistent state it had before; the
+    /// state machine is restored from the snapshot. The cluster configuration
+    /// is the latest one found in the log or the snapshot, and is made of this
+    /// server and `peers` until a configuration entry is appended.
+    ///
+    /// # Arguments
+    /// * `server_id` - Unique identifier for this server in the cluster
+    /// * `peers` - IDs of the other servers in the initial configuration
+    /// * `state_machine` - Application the committed entries are applied to
+    /// * `storage` - Stable storage for the persistent state
+    pub(crate) fn new(
+        server_id: u64,
+        peers: Vec<u64>,
+        mut state_machine: Box<dyn StateMachine>,
+        storage: Box<dyn RaftStorage>,
+    ) -> Result<Self, StorageError> {
+        let mut state = RaftState::new(server_id);
+        let hard_state = storage.load_hard_state()?;
+        state.current_term = hard_state.current_term;
+        state.voted_for = hard_state.voted_for;
+        let mut applied_configuration = Configuration::new(peers.into_iter().chain([server_id]));
+        if let Some(snapshot) = storage.load_snapshot()? {
+            state_machine
+                .restore(&snapshot.data)
+                .map_err(|error| StorageError::Corrupted(format!("invalid snapshot: {}", error)))?;
+            state.snapshot_position = snapshot.last_included.clone();
+            state.commit_position = snapshot.last_included.clone();
+            state.last_applied = snapshot.last_included;
+            applied_configuration = snapshot.configuration;
+        }
+        state.logs = storage.entries(storage.first_index(), storage.last_index() + 1)?;
+
+        let now = Instant::now();
+        let mut core = Self {
+            state,
+            peers: Vec::new(),
+            leader_id: None,
+            configuration: applied_configuration.clone(),
+            configuration_index: 0,
+            applied_configuration,
+            state_machine,
+            storage,
+            config: RaftConfig::default(),
+            pending_responses: HashMap::new(),
+            pending_reads: VecDeque::new(),
+            read_sequence: 0,
+            round_starts: VecDeque::new(),
+            lease_expiry: None,
+            forwarded_reads: HashMap::new(),
+            forwarded_read_sequence: 0,
+            progress: BTreeMap::new(),
+            leadership_transfer: None,
+            membership_change: None,
+            held_append_response: None,
+            incoming_snapshot: None,
+            snapshot_chunk_size: SNAPSHOT_CHUNK_SIZE,
+            votes_granted: HashSet::new(),
+            last_leader_contact: None,
+            election_deadline: now + random_election_timeout(&RaftConfig::default()),
+            heartbeat_deadline: now,
+            quorum_check_deadline: now,
+            outbox: Vec::new(),
+        };
+        core.refresh_configuration();
+        Ok(core)
+    }
+
+    /// Drains the messages queued since the last call
+    pub(crate) fn take_outbox(&mut self) -> Vec<(u64, RpcMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Advances the election and heartbeat timers
+    ///
+    /// Leaders send a heartbeat once the heartbeat interval has elapsed and, with
+    /// CheckQuorum, step down if a majority did not answer within the last
+    /// election timeout; followers and candidates start a new election (or
+    /// PreVote round) once the election timeout has elapsed, unless they are
+    /// not a voter of the current configuration.
+    pub(crate) fn tick(&mut self) {
+        let now = Instant::now();
+        match self.state.state {
+            State::Leader => {
+                if self.config.check_quorum && now >= self.quorum_check_deadline {
+                    self.quorum_check_deadline = now + self.config.election_timeout_max;
+                    if !self.has_quorum_contact(now) {
+                        log::warn!(
+                            "Server {} lost contact with a majority, stepping down in term {}",
+                            self.state.server_id,
+                            self.state.current_term
+                        );
+                        self.become_follower(self.state.current_term, None);
+                        return;
+                    }
+                }
+                self.check_leadership_transfer(now);
+                if now >= self.heartbeat_deadline {
+                    self.broadcast_append();
+                }
+            }
+            State::Follower | State::PreCandidate | State::Candidate => {
+                if now >= self.election_deadline {
+                    if !self.configuration.is_voter(self.state.server_id) {
+                        self.reset_election_deadline();
+                    } else if self.config.pre_vote {
+                        self.pre_campaign();
+                    } else {
+                        self.campaign(false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Processes an RPC message received from `from`
+    ///
+    /// A message carrying a higher term always converts this server to a
+    /// follower of that term before the message itself is handled, except for a
+    /// PreVote, whose term was not started by anyone yet.
+    ///
+    /// With CheckQuorum, a RequestVote is ignored altogether while we have a
+    /// live leader, so that a server that lost touch with the leader cannot
+    /// depose it by bumping everyone's term. A candidate the leader handed its
+    /// leadership to is the exception.
+    pub(crate) fn step(&mut self, from: u64, message: RpcMessage) {
+        if let RpcMessage::RequestVote(request) = &message
+            && self.config.check_quorum
+            && !request.leadership_transfer
+            && request.current_term > self.state.current_term
+            && self.leader_alive()
+        {
+            log::debug!(
+                "Server {} ignores vote request from {} for term {}: the leader is alive",
+                self.state.server_id,
+                request.candidate_id,
+                request.current_term
+            );
+            return;
+        }
+
+        if message.term() > self.state.current_term && !matches!(message, RpcMessage::PreVote(_)) {
+            let leader_id = match &message {
+                RpcMessage::AppendEntry(request) => Some(request.leader_id),
+                RpcMessage::InstallSnapshot(request) => Some(request.leader_id),
+                _ => None,
+            };
+            self.become_follower(message.term(), leader_id);
+        }
+
+        match message {
+            RpcMessage::PreVote(request) => self.handle_pre_vote(from, request),
+            RpcMessage::PreVoteResponse(response) => self.handle_pre_vote_response(from, response),
+            RpcMessage::RequestVote(request) => self.handle_request_vote(from, request),
+            RpcMessage::RequestVoteResponse(response) => {
+                self.handle_request_vote_response(from, response)
+            }
+            RpcMessage::AppendEntry(request) => self.handle_append_entry(from, request),
+            RpcMessage::AppendEntryResponse(response) => {
+                self.handle_append_entry_response(from, response)
+            }
+            RpcMessage::InstallSnapshot(request) => self.handle_install_snapshot(from, request),
+            RpcMessage::InstallSnapshotResponse(response) => {
+                self.handle_install_snapshot_response(from, response)
+            }
+            RpcMessage::TimeoutNow(request) => self.handle_timeout_now(request),
+            RpcMessage::ReadIndex(request) => self.handle_read_index(from, request),
+            RpcMessage::ReadIndexResponse(response) => self.handle_read_index_response(from, response),
+        }
+    }
+
+    /// Applies every committed entry not applied yet, in log order, then takes
+    /// a snapshot if the snapshot policy asks for one and answers the reads
+    /// waiting for these entries
+    ///
+    /// Configuration entries are not passed to the state machine, but may move
+    /// a membership change forward (see `on_configuration_applied`).
+    ///
+    /// The response of each entry is routed to the client that proposed it, if it
+    /// was proposed on this server and the entry at that index is still the one
+    /// that was proposed (a new leader may have overwritten it).
+    pub(crate) fn apply_committed(&mut self) {
+        if self.state.last_applied.index >= self.state.commit_position.index {
+            return;
+        }
+        while self.state.last_applied.index < self.state.commit_position.index {
+            let index = self.state.last_applied.index + 1;
+            let entry = self.state.entry_at(index).expect("committed entry is in the log");
+            let (term, kind) = (entry.term, entry.kind);
+            let response = match kind {
+                EntryKind::Command => self.state_machine.apply(index, &entry.payload),
+                EntryKind::Configuration => {
+                    self.applied_configuration = decode_configuration(entry).expect(CHECKED_CONFIGURATION);
+                    Vec::new()
+                }
+                EntryKind::Noop => Vec::new(),
+            };
+            self.state.last_applied = LogPosition::new(term, index);
+            if kind == EntryKind::Configuration {
+                self.on_configuration_applied(index);
+            }
+
+            if let Some((proposed_term, client)) = self.pending_responses.remove(&index) {
+                let result = if proposed_term == term { Ok(response) } else { Err(ProposeError::Dropped) };
+                // The client may have given up waiting
+                let _ = client.send(ProposeResponse { result });
+            }
+        }
+        self.maybe_snapshot();
+        self.serve_reads();
+    }
+
+    /// Converts this server to a follower of `term`
+    pub(crate) fn become_follower(&mut self, term: u64, leader_id: Option<u64>) {
+        if term > self.state.current_term {
+            self.state.current_term = term;
+            self.state.voted_for = None;
+            self.save_hard_state();
+            self.last_leader_contact = None;
+        }
+        if self.state.state != State::Follower {
+            log::info!("Server {} became follower in term {}", self.state.server_id, term);
+        }
+        self.state.state = State::Follower;
+        self.leader_id = leader_id;
+        self.leadership_transfer = None;
+        self.lease_expiry = None;
+        self.fail_pending_reads();
+        self.fail_forwarded_reads();
+        if let Some(client) = self.membership_change.take() {
+            let _ = client.send(Err(MembershipError::LeadershipLost));
+        }
+        self.reset_election_deadline();
+    }
+
+    /// Converts this server to a candidate, starting a new term and voting for itself
+    pub(crate) fn become_candidate(&mut self) {
+        self.state.current_term += 1;
+        self.state.voted_for = Some(self.state.server_id);
+        self.save_hard_state();
+        self.state.state = State::Candidate;
+        self.leader_id = None;
+        self.fail_forwarded_reads();
+        self.votes_granted.clear();
+        self.votes_granted.insert(self.state.server_id);
+        self.reset_election_deadline();
+        log::info!(
+            "Server {} became candidate in term {}",
+            self.state.server_id,
+            self.state.current_term
+        );
+    }
+
+    /// Converts this server to the leader of the current term
+    ///
+    /// Every follower is initially assumed to hold the leader's whole log; the
+    /// consistency check of AppendEntry walks `next_index` back where it does not.
+    pub(crate) fn become_leader(&mut self) {
+        self.state.state = State::Leader;
+        self.leader_id = Some(self.state.server_id);
+        self.fail_forwarded_reads();
+
+        let next_index = self.state.last_log_index() + 1;
+        self.progress = self.peers.iter().map(|&peer| (peer, Progress::new(next_index, None))).collect();
+        self.round_starts.clear();
+        self.leadership_transfer = None;
+        self.quorum_check_deadline = Instant::now() + self.config.election_timeout_max;
+
+        log::info!(
+            "Server {} became leader in term {}",
+            self.state.server_id,
+            self.state.current_term
+        );
+        // Entries of earlier terms only commit along with one of the current
+        // term (Raft paper, section 5.4.2), so a no-op commits them right away
+        let noop = LogEntry::noop(self.state.current_term, self.state.last_log_index() + 1);
+        self.append_to_log(vec![noop]);
+        self.broadcast_append();
+        self.advance_commit();
+        // Completes a membership change the previous leader left halfway
+        if self.configuration.is_joint() && self.configuration_index <= self.state.last_applied.index {
+            self.on_configuration_applied(self.configuration_index);
+        }
+    }
+
+    /// Whether this server is the leader or heard from the leader of the
+    /// current term within the minimum election timeout
+    fn leader_alive(&self) -> bool {
+        self.state.state == State::Leader
+            || self
+                .last_leader_contact
+                .is_some_and(|contact| contact.elapsed() < self.config.election_timeout_min)
+    }
+
+    /// Whether a majority, counting this leader, answered within the last
+    /// election timeout
+    fn has_quorum_contact(&self, now: Instant) -> bool {
+        self.configuration.has_majority(|id| {
+            id == self.state.server_id
+                || self
+                    .progress
+                    .get(&id)
+                    .and_then(|progress| progress.last_contact)
+                    .is_some_and(|contact| now.duration_since(contact) <= self.config.election_timeout_max)
+        })
+    }
+
+    /// Whether the servers that (pre-)voted for us form a majority
+    fn has_vote_majority(&self) -> bool {
+        self.configuration.has_majority(|id| self.votes_granted.contains(&id))
+    }
+
+    /// Starts a PreVote round: polls every peer for the vote it would grant in
+    /// the next term, without changing our own term
+    fn pre_campaign(&mut self) {
+        if self.state.state != State::PreCandidate {
+            log::info!(
+                "Server {} became pre-candidate in term {}",
+                self.state.server_id,
+                self.state.current_term
+            );
+        }
+        self.state.state = State::PreCandidate;
+        self.leader_id = None;
+        self.fail_forwarded_reads();
+        self.votes_granted.clear();
+        self.votes_granted.insert(self.state.server_id);
+        self.reset_election_deadline();
+        if self.has_vote_majority() {
+            self.campaign(false);
+            return;
+        }
+
+        let request = PreVoteRequest {
+            next_term: self.state.current_term + 1,
+            candidate_id: self.state.server_id,
+            last_log_index: self.state.last_log_index(),
+            last_log_term: self.state.last_log_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, RpcMessage::PreVote(request.clone()));
+        }
+    }
+
+    /// Answers whether we would vote for the sender in its next term
+    ///
+    /// The vote would be refused while we have a live leader: a server that
+    /// merely lost touch with it must not be able to depose it. Nothing is
+    /// persisted since the answer commits us to nothing.
+    fn handle_pre_vote(&mut self, from: u64, request: PreVoteRequest) {
+        let vote_granted = request.next_term > self.state.current_term
+            && !self.leader_alive()
+            && self.state.is_log_up_to_date(request.last_log_index, request.last_log_term);
+
+        let response = PreVoteResponse {
+            term: self.state.current_term,
+            vote_granted,
+            next_term: request.next_term,
+        };
+        self.send(from, RpcMessage::PreVoteResponse(response));
+    }
+
+    /// Counts the grant toward the current PreVote round; a grant for the term
+    /// of an earlier round says nothing about the next one
+    fn handle_pre_vote_response(&mut self, from: u64, response: PreVoteResponse) {
+        if self.state.state != State::PreCandidate
+            || response.next_term != self.state.current_term + 1
+            || !response.vote_granted
+        {
+            return;
+        }
+
+        self.votes_granted.insert(from);
+        if self.has_vote_majority() {
+            self.campaign(false);
+        }
+    }
+
+    /// Starts an election: becomes candidate and requests votes from every peer
+    ///
+    /// `leadership_transfer` marks an election the leader asked for through TimeoutNow.
+    pub(crate) fn campaign(&mut self, leadership_transfer: bool) {
+        self.become_candidate();
+        if self.has_vote_majority() {
+            self.become_leader();
+            return;
+        }
+
+        let request = RequestVoteRequest {
+            current_term: self.state.current_term,
+            candidate_id: self.state.server_id,
+            last_log_index: self.state.last_log_index(),
+            last_log_term: self.state.last_log_term(),
+            leadership_transfer,
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, RpcMessage::RequestVote(request.clone()));
+        }
+    }
+
+    /// Grants the vote if the candidate's term is current, this server has not
+    /// voted for another candidate in this term, and the candidate's log is at
+    /// least as up-to-date as ours
+    fn handle_request_vote(&mut self, from: u64, request: RequestVoteRequest) {
+        let vote_granted = request.current_term == self.state.current_term
+            && self.state.voted_for.is_none_or(|candidate| candidate == request.candidate_id)
+            && self.state.is_log_up_to_date(request.last_log_index, request.last_log_term);
+        if vote_granted {
+            // Persisted before replying so that a second candidate of the same
+            // term is refused, even after a restart
+            self.state.voted_for = Some(request.candidate_id);
+            self.save_hard_state();
+            self.reset_election_deadline();
+        }
+        log::debug!(
+            "Server {} {} vote for {} in term {}",
+            self.state.server_id,
+            if vote_granted { "granted" } else { "refused" },
+            request.candidate_id,
+            request.current_term
+        );
+
+        let response = RequestVoteResponse {
+            term: self.state.current_term,
+            vote_granted,
+        };
+        self.send(from, RpcMessage::RequestVoteResponse(response));
+    }
+
+    fn handle_request_vote_response(&mut self, from: u64, response: RequestVoteResponse) {
+        if self.state.state != State::Candidate
+            || response.term != self.state.current_term
+            || !response.vote_granted
+        {
+            return;
+        }
+
+        self.votes_granted.insert(from);
+        if self.has_vote_majority() {
+            self.become_leader();
+        }
+    }
+
+    /// Durably saves `current_term` and `voted_for`
+    pub(crate) fn save_hard_state(&mut self) {
+        let hard_state = HardState {
+            current_term: self.state.current_term,
+            voted_for: self.state.voted_for,
+        };
+        if let Err(error) = self.storage.save_hard_state(&hard_state) {
+            storage_failure(error);
+        }
+    }
+
+    /// Starts making every appended entry durable, see `RaftStorage::start_sync`
+    ///
+    /// The returned fsync is run without the core, then its result is passed
+    /// to `finish_storage_sync`. If the storage synced in place, what waited
+    /// for it is sent right away.
+    pub(crate) fn start_storage_sync(&mut self) -> Option<PendingSync> {
+        match self.storage.start_sync() {
+            Ok(Some(sync)) => Some(sync),
+            Ok(None) => {
+                self.on_log_durable();
+                None
+            }
+            Err(error) => storage_failure(error),
+        }
+    }
+
+    /// Records the outcome of a fsync started by `start_storage_sync`, then
+    /// sends what waited for it
+    pub(crate) fn finish_storage_sync(&mut self, result: Result<(), StorageError>) {
+        if let Err(error) = result {
+            storage_failure(error);
+        }
+        self.storage.finish_sync();
+        self.on_log_durable();
+    }
+
+    /// Makes every appended entry durable, then sends what waited for it
+    #[cfg(test)]
+    pub(crate) fn sync_storage(&mut self) {
+        if let Some(sync) = self.start_storage_sync() {
+            self.finish_storage_sync(sync.run());
+        }
+    }
+
+    /// Appends entries to stable storage, then to the in-memory log; the first
+    /// entry must directly follow the last entry of the log
+    ///
+    /// The entries may not be durable yet when this returns, see `RaftStorage`.
+    /// The leader never waits for them: it replicates them while they are
+    /// written, and only counts itself toward their quorum once they are
+    /// durable (see `advance_commit`). A configuration entry takes effect as
+    /// soon as it is appended.
+    pub(crate) fn append_to_log(&mut self, entries: Vec<LogEntry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let appended = if self.state.state == State::Leader {
+            self.storage.append_deferred(&entries)
+        } else {
+            self.storage.append(&entries)
+        };
+        if let Err(error) = appended {
+            storage_failure(error);
+        }
+        let configuration = entries
+            .iter()
+            .rfind(|entry| entry.kind == EntryKind::Configuration)
+            .map(|entry| (decode_configuration(entry).expect(CHECKED_CONFIGURATION), entry.index));
+        self.state.append_entries(entries);
+        if let Some((configuration, index)) = configuration {
+            self.set_configuration(configuration, index);
+        }
+    }
+
+    /// Durably removes the entry at `index` and every following entry, reverting
+    /// to the previous configuration if the latest one is removed
+    pub(crate) fn truncate_log(&mut self, index: u64) {
+        if let Err(error) = self.storage.truncate(index) {
+            storage_failure(error);
+        }
+        self.state.truncate_from(index);
+        if self.configuration_index >= index {
+            self.refresh_configuration();
+        }
+    }
+
+    pub(crate) fn reset_election_deadline(&mut self) {
+        self.election_deadline = Instant::now() + random_election_timeout(&self.config);
+    }
+
+    pub(crate) fn send(&mut self, to: u64, message: RpcMessage) {
+        self.outbox.push((to, message));
+    }
+}
+
+/// Stops the server after a failed write to stable storage
+///
+/// Raft's safety relies on persisted state never being lost, and once a write
+/// has failed we cannot tell what reached the disk, so the server must stop
+/// taking part in the protocol rather than answer with state it may forget.
+pub(crate) fn storage_failure(error: StorageError) -> ! {
+    log::error!("Stable storage failed, stopping the server: {}", error);
+    panic!("stable storage failed: {}", error);
+}
+
+/// Picks a new election timeout in the configured range
+///
+/// Randomizing the timeout makes it unlikely that several followers time out
+/// together and split the vote (Raft paper, section 5.2).
+fn random_election_timeout(config: &RaftConfig) -> Duration {
+    rand::rng().random_range(config.election_timeout_min..=config.election_timeout_max)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::log::SegmentLog;
+    use crate::rpc::AppendEntryRequest;
+    use crate::state_machine::tests::EchoStateMachine;
+    use crate::storage::memory_storage::MemoryStorage;
+
+    pub(crate) fn new_core(server_id: u64, peers: Vec<u64>) -> NodeCore {
+        let state_machine = Box::new(EchoStateMachine::default());
+        NodeCore::new(server_id, peers, state_machine, Box::new(MemoryStorage::new())).unwrap()
+    }
+
+    /// Appends commands of `term` at the end of the core's log
+    pub(crate) fn append_commands(core: &mut NodeCore, term: u64, commands: Vec<Vec<u8>>) {
+        let first_index = core.state.last_log_index() + 1;
+        let entries = (first_index..).zip(commands).map(|(index, command)| LogEntry::command(term, index, command));
+        core.append_to_log(entries.collect());
+    }
+
+    fn vote_request(term: u64, candidate_id: u64) -> RpcMessage {
+        RpcMessage::RequestVote(RequestVoteRequest {
+            current_term: term,
+            candidate_id,
+            last_log_index: 0,
+            last_log_term: 0,
+            leadership_transfer: false,
+        })
+    }
+
+    fn granted_votes(core: &mut NodeCore) -> Vec<bool> {
+        core.take_outbox()
+            .into_iter()
+            .map(|(_, message)| match message {
+                RpcMessage::RequestVoteResponse(response) => response.vote_granted,
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_node_becomes_leader_on_election_timeout() {
+        let mut core = new_core(1, vec![]);
+        core.election_deadline = Instant::now();
+
+        core.tick();
+
+        assert_eq!(core.state.state, State::Leader);
+        assert_eq!(core.state.current_term, 1);
+        assert_eq!(core.leader_id, Some(1));
+    }
+
+    #[test]
+    fn candidate_becomes_leader_with_majority() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.election_deadline = Instant::now();
+        core.tick();
+        assert_eq!(core.state.state, State::Candidate);
+        assert_eq!(core.take_outbox().len(), 2);
+
+        core.step(2, RpcMessage::RequestVoteResponse(RequestVoteResponse {
+            term: 1,
+            vote_granted: true,
+        }));
+
+        assert_eq!(core.state.state, State::Leader);
+    }
+
+    #[test]
+    fn grants_a_single_vote_per_term() {
+        let mut core = new_core(1, vec![2, 3]);
+
+        core.step(2, vote_request(1, 2));
+        core.step(3, vote_request(1, 3));
+
+        assert_eq!(granted_votes(&mut core), vec![true, false]);
+        assert_eq!(core.state.voted_for, Some(2));
+        assert_eq!(
+            core.storage.load_hard_state().unwrap(),
+            HardState { current_term: 1, voted_for: Some(2) }
+        );
+    }
+
+    #[test]
+    fn restores_persistent_state_from_storage() {
+        let mut storage = MemoryStorage::new();
+        storage.save_hard_state(&HardState { current_term: 4, voted_for: Some(3) }).unwrap();
+        storage.append(&[LogEntry::command(2, 1, vec![1]), LogEntry::command(2, 2, vec![2])]).unwrap();
+
+        let state_machine = Box::new(EchoStateMachine::default());
+        let core = NodeCore::new(1, vec![2, 3], state_machine, Box::new(storage)).unwrap();
+
+        assert_eq!(core.state.current_term, 4);
+        assert_eq!(core.state.voted_for, Some(3));
+        assert_eq!(core.state.last_log_index(), 2);
+        assert_eq!(core.state.last_log_term(), 2);
+    }
+
+    #[test]
+    fn refuses_vote_to_candidate_with_stale_log() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.state.current_term = 2;
+        core.state.logs.push(SegmentLog::new(2, vec![LogEntry::command(2, 1, vec![1])]));
+
+        // Older last term loses even with a longer log
+        core.step(2, RpcMessage::RequestVote(RequestVoteRequest {
+            current_term: 3,
+            candidate_id: 2,
+            last_log_index: 5,
+            last_log_term: 1,
+            leadership_transfer: false,
+        }));
+        // Same last term but shorter log
+        core.step(3, RpcMessage::RequestVote(RequestVoteRequest {
+            current_term: 3,
+            candidate_id: 3,
+            last_log_index: 0,
+            last_log_term: 0,
+            leadership_transfer: false,
+        }));
+
+        assert_eq!(granted_votes(&mut core), vec![false, false]);
+        assert_eq!(core.state.voted_for, None);
+    }
+
+    #[test]
+    fn steps_down_on_higher_term() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.election_deadline = Instant::now();
+        core.tick();
+
+        core.step(2, RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: 5,
+            leader_id: 2,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            append_index: 1,
+            entries: vec![],
+            leader_commit: 0,
+            read_id: 0,
+        }));
+
+        assert_eq!(core.state.state, State::Follower);
+        assert_eq!(core.state.current_term, 5);
+        assert_eq!(core.leader_id, Some(2));
+    }
+
+    fn pre_vote_request(next_term: u64, candidate_id: u64) -> RpcMessage {
+        RpcMessage::PreVote(PreVoteRequest {
+            next_term,
+            candidate_id,
+            last_log_index: 0,
+            last_log_term: 0,
+        })
+    }
+
+    #[test]
+    fn pre_candidate_keeps_its_term_until_a_majority_would_vote() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.config.pre_vote = true;
+        core.election_deadline = Instant::now();
+        core.tick();
+
+        assert_eq!(core.state.state, State::PreCandidate);
+        assert_eq!(core.state.current_term, 0);
+        assert!(core.take_outbox().iter().all(|(_, message)| matches!(message, RpcMessage::PreVote(_))));
+
+        core.step(2, RpcMessage::PreVoteResponse(PreVoteResponse { term: 0, vote_granted: true, next_term: 1 }));
+        assert_eq!(core.state.state, State::Candidate);
+        assert_eq!(core.state.current_term, 1);
+    }
+
+    #[test]
+    fn pre_vote_grant_of_an_earlier_term_is_not_counted() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.config.pre_vote = true;
+        core.become_follower(2, None);
+        core.election_deadline = Instant::now();
+        core.tick();
+        assert_eq!(core.state.state, State::PreCandidate);
+
+        core.step(2, RpcMessage::PreVoteResponse(PreVoteResponse { term: 0, vote_granted: true, next_term: 1 }));
+        assert_eq!(core.state.state, State::PreCandidate);
+
+        core.step(2, RpcMessage::PreVoteResponse(PreVoteResponse { term: 2, vote_granted: true, next_term: 3 }));
+        assert_eq!(core.state.state, State::Candidate);
+        assert_eq!(core.state.current_term, 3);
+    }
+
+    #[test]
+    fn refuses_pre_vote_while_the_leader_is_alive() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.step(2, RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: 1,
+            leader_id: 2,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            append_index: 1,
+            entries: vec![],
+            leader_commit: 0,
+            read_id: 0,
+        }));
+        core.take_outbox();
+
+        core.step(3, pre_vote_request(2, 3));
+
+        match core.take_outbox().pop() {
+            Some((3, RpcMessage::PreVoteResponse(response))) => assert!(!response.vote_granted),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!(core.state.current_term, 1);
+    }
+
+    #[test]
+    fn grants_pre_vote_without_a_leader_and_keeps_its_term() {
+        let mut core = new_core(1, vec![2, 3]);
+
+        core.step(3, pre_vote_request(1, 3));
+
+        match core.take_outbox().pop() {
+            Some((3, RpcMessage::PreVoteResponse(response))) => assert!(response.vote_granted),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!(core.state.current_term, 0);
+        assert_eq!(core.state.voted_for, None);
+    }
+
+    pub(crate) fn elected_leader(peers: Vec<u64>) -> NodeCore {
+        let mut core = new_core(1, peers.clone());
+        core.election_deadline = Instant::now();
+        core.tick();
+        for peer in peers {
+            core.step(peer, RpcMessage::RequestVoteResponse(RequestVoteResponse { term: 1, vote_granted: true }));
+        }
+        core.take_outbox();
+        assert_eq!(core.state.state, State::Leader);
+        core
+    }
+
+    #[test]
+    fn leader_steps_down_without_contact_with_a_majority() {
+        let mut core = elected_leader(vec![2, 3, 4, 5]);
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 0, read_id: 0, ..Default::default() }));
+
+        core.quorum_check_deadline = Instant::now();
+        core.tick();
+
+        assert_eq!(core.state.state, State::Follower);
+        assert_eq!(core.state.current_term, 1);
+    }
+
+    #[test]
+    fn leader_keeps_its_role_while_a_majority_answers() {
+        let mut core = elected_leader(vec![2, 3, 4, 5]);
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 0, read_id: 0, ..Default::default() }));
+        // A rejected append still shows the follower is reachable
+        core.step(4, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: false, match_index: 0, read_id: 0, ..Default::default() }));
+
+        core.quorum_check_deadline = Instant::now();
+        core.tick();
+
+        assert_eq!(core.state.state, State::Leader);
+    }
+
+    #[test]
+    fn ignores_vote_requests_while_the_leader_is_alive() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.step(2, RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: 1,
+            leader_id: 2,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            append_index: 1,
+            entries: vec![],
+            leader_commit: 0,
+            read_id: 0,
+        }));
+        core.take_outbox();
+
+        core.step(3, vote_request(2, 3));
+
+        assert!(core.take_outbox().is_empty());
+        assert_eq!(core.state.current_term, 1);
+        assert_eq!(core.leader_id, Some(2));
+    }
+}
@@ -0,0 +1,249 @@
+//! Leader-side replication progress of every peer, keyed by node ID, and the
+//! flow control bounding the AppendEntry in flight to each of them.
+
+use std::collections::VecDeque;
+
+use tokio::time::Instant;
+
+use super::snapshot::SnapshotTransfer;
+
+/// How much the leader sends to a peer in Replicate without waiting for its
+/// answers
+///
+/// A new AppendEntry carrying entries is sent as long as both limits hold;
+/// a single AppendEntry larger than `max_in_flight_bytes` is still sent once
+/// nothing else is in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowControl {
+    pub max_in_flight_appends: usize,   // AppendEntry carrying entries awaiting a response, at least 1
+    pub max_in_flight_bytes: u64,       // Payload bytes of the entries awaiting a response
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self {
+            max_in_flight_appends: 64,
+            max_in_flight_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// How the leader currently replicates to a peer
+#[derive(Debug, Clone)]
+pub(crate) enum ProgressState {
+    /// Where the peer's log stops matching ours is unknown: `next_index` walks
+    /// back on every rejection, one AppendEntry at a time
+    Probe,
+    /// The peer's log matches ours up to `match_index`: new entries are sent
+    /// without waiting for earlier ones to be acknowledged, as long as the
+    /// in-flight window allows, and `next_index` moves past them as they are sent
+    Replicate,
+    /// The peer needs compacted entries and is sent the snapshot, one chunk at
+    /// a time
+    Snapshot(SnapshotTransfer),
+}
+
+/// What the leader knows about a peer's log (`nextIndex` and `matchIndex` of
+/// the Raft paper, figure 2), and the requests still awaiting its answer
+#[derive(Debug, Clone)]
+pub(crate) struct Progress {
+    pub(crate) match_index: u64,                 // Highest entry known to be replicated on the peer
+    pub(crate) next_index: u64,                  // Next entry to send to the peer
+    pub(crate) state: ProgressState,
+    pub(crate) in_flight: VecDeque<(u64, u64)>,  // Last index and payload bytes of every AppendEntry awaiting a response, oldest first
+    pub(crate) in_flight_bytes: u64,             // Payload bytes of all of `in_flight`
+    pub(crate) last_contact: Option<Instant>,    // When the peer last answered in the current term
+    pub(crate) read_ack: u64,                    // Latest heartbeat round the peer acknowledged
+}
+
+impl Progress {
+    /// Creates the progress of a peer whose log is unknown, probed from
+    /// `next_index` on
+    pub(crate) fn new(next_index: u64, last_contact: Option<Instant>) -> Self {
+        Self {
+            match_index: 0,
+            next_index,
+            state: ProgressState::Probe,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            last_contact,
+            read_ack: 0,
+        }
+    }
+
+    /// Whether new entries must wait for an answer before being sent
+    ///
+    /// Snapshot chunks are only sent in answer to the previous chunk or with
+    /// the heartbeat, so a peer receiving the snapshot is always paused.
+    pub(crate) fn is_paused(&self, flow_control: &FlowControl) -> bool {
+        match self.state {
+            ProgressState::Probe => !self.in_flight.is_empty(),
+            ProgressState::Replicate => {
+                self.in_flight.len() >= flow_control.max_in_flight_appends
+                    || self.in_flight_bytes >= flow_control.max_in_flight_bytes
+            }
+            ProgressState::Snapshot(_) => true,
+        }
+    }
+
+    /// Records an AppendEntry carrying `bytes` of entries up to `last_index`
+    ///
+    /// A retransmission of entries already in flight takes no extra room. In
+    /// Replicate, the next AppendEntry carries the entries following these
+    /// without waiting for the answer.
+    pub(crate) fn sent(&mut self, last_index: u64, bytes: u64) {
+        if self.in_flight.back().is_none_or(|&(last, _)| last < last_index) {
+            self.in_flight.push_back((last_index, bytes));
+            self.in_flight_bytes += bytes;
+        }
+        if matches!(self.state, ProgressState::Replicate) {
+            self.next_index = self.next_index.max(last_index + 1);
+        }
+    }
+
+    /// Records that the peer stores every entry up to `match_index`, from an
+    /// AppendEntry or the snapshot it installed
+    ///
+    /// Acknowledgements are cumulative, so every AppendEntry up to
+    /// `match_index` leaves the in-flight window, even one whose answer was
+    /// lost. Entries sent past `match_index` in Replicate stay in flight.
+    pub(crate) fn acknowledge(&mut self, match_index: u64) {
+        self.match_index = self.match_index.max(match_index);
+        self.next_index = match self.state {
+            ProgressState::Replicate => self.next_index.max(self.match_index + 1),
+            _ => self.match_index + 1,
+        };
+        while let Some(&(last_index, bytes)) = self.in_flight.front() {
+            if last_index > self.match_index {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.in_flight_bytes -= bytes;
+        }
+        self.state = ProgressState::Replicate;
+    }
+
+    /// Walks `next_index` back to `resume_index` after the peer rejected the
+    /// AppendEntry following `rejected_index`, and probes from there
+    ///
+    /// `next_index` moves back by at least one entry, but never past an entry
+    /// the peer is known to hold. Whatever was pipelined after the rejected
+    /// AppendEntry is dropped from the window and sent again once probing succeeds.
+    ///
+    /// # Returns
+    /// * `false`, leaving the progress unchanged, if the rejection is stale: it
+    ///   answers an AppendEntry other than the current probe, or one the peer
+    ///   has since acknowledged entries past
+    pub(crate) fn reject(&mut self, rejected_index: u64, resume_index: u64) -> bool {
+        let stale = match self.state {
+            ProgressState::Probe => rejected_index != self.next_index - 1,
+            _ => rejected_index <= self.match_index,
+        };
+        if stale {
+            return false;
+        }
+        self.next_index = resume_index.min(self.next_index - 1).max(self.match_index + 1);
+        self.clear_in_flight();
+        self.state = ProgressState::Probe;
+        true
+    }
+
+    /// Starts sending `transfer`'s snapshot in place of entries
+    pub(crate) fn send_snapshot(&mut self, transfer: SnapshotTransfer) {
+        self.clear_in_flight();
+        self.state = ProgressState::Snapshot(transfer);
+    }
+
+    fn clear_in_flight(&mut self) {
+        self.in_flight.clear();
+        self.in_flight_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acknowledgement_frees_every_append_it_covers() {
+        let flow_control = FlowControl::default();
+        let mut progress = Progress::new(1, None);
+        progress.sent(3, 10);
+        assert!(progress.is_paused(&flow_control));
+
+        // A retransmission of the same entries takes no extra room
+        progress.acknowledge(3);
+        progress.sent(5, 10);
+        progress.sent(5, 10);
+        assert_eq!(progress.in_flight, [(5, 10)]);
+        assert!(matches!(progress.state, ProgressState::Replicate));
+
+        progress.acknowledge(5);
+        assert!(!progress.is_paused(&flow_control));
+        assert_eq!((progress.match_index, progress.next_index, progress.in_flight_bytes), (5, 6, 0));
+    }
+
+    #[test]
+    fn replicate_pipelines_appends_within_the_window() {
+        let flow_control = FlowControl { max_in_flight_appends: 3, max_in_flight_bytes: 100 };
+        let mut progress = Progress::new(1, None);
+        progress.acknowledge(0);
+
+        progress.sent(2, 10);
+        progress.sent(4, 10);
+        assert_eq!(progress.next_index, 5);
+        assert!(!progress.is_paused(&flow_control));
+        progress.sent(5, 10);
+        assert!(progress.is_paused(&flow_control));
+
+        // An acknowledgement of the first append reopens the window without
+        // sending the others again
+        progress.acknowledge(2);
+        assert_eq!((progress.match_index, progress.next_index), (2, 6));
+        assert!(!progress.is_paused(&flow_control));
+
+        // The byte budget closes the window too
+        progress.acknowledge(4);
+        progress.sent(6, 90);
+        assert_eq!(progress.in_flight.len(), 2);
+        assert!(progress.is_paused(&flow_control));
+    }
+
+    #[test]
+    fn rejection_walks_back_at_least_one_entry_but_not_past_the_match_index() {
+        let flow_control = FlowControl::default();
+        let mut progress = Progress::new(1, None);
+        progress.acknowledge(4);
+        progress.next_index = 9;
+        progress.sent(10, 10);
+        assert_eq!(progress.next_index, 11);
+
+        assert!(progress.reject(8, 11));
+        assert_eq!(progress.next_index, 10);
+        assert!(matches!(progress.state, ProgressState::Probe) && !progress.is_paused(&flow_control));
+        assert_eq!(progress.in_flight_bytes, 0);
+
+        assert!(progress.reject(9, 2));
+        assert_eq!(progress.next_index, 5);
+    }
+
+    #[test]
+    fn stale_rejections_are_ignored() {
+        let mut progress = Progress::new(1, None);
+        progress.acknowledge(4);
+        progress.sent(6, 10);
+        progress.sent(8, 10);
+
+        // The rejection of the second pipelined AppendEntry starts probing...
+        assert!(progress.reject(6, 5));
+        assert_eq!(progress.next_index, 5);
+        // ...and a duplicate of it is ignored
+        assert!(!progress.reject(6, 5));
+        assert_eq!(progress.next_index, 5);
+
+        // So is a rejection of entries the peer acknowledged since
+        progress.acknowledge(7);
+        assert!(!progress.reject(5, 1));
+        assert_eq!((progress.match_index, progress.next_index), (7, 8));
+    }
+}
@@ -0,0 +1,226 @@
+//! Client proposals: commands submitted to the leader that resolve with the
+//! state machine output once they are committed and applied, and the batching
+//! that coalesces concurrent proposals into a single append.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant, timeout_at};
+
+use crate::channel::message::Message;
+use crate::channel::payload::{Request, Response};
+use crate::log::EntryKind;
+
+use super::node_core::NodeCore;
+
+/// A command submitted to the cluster through `RaftNode::propose`
+#[derive(Debug, Clone)]
+pub struct Proposal {
+    pub command: Vec<u8>,
+}
+
+impl Request for Proposal {}
+
+/// Outcome of a proposal, sent back once the command is applied or rejected
+#[derive(Debug, Clone)]
+pub struct ProposeResponse {
+    pub result: Result<Vec<u8>, ProposeError>,
+}
+
+impl Response for ProposeResponse {}
+
+/// Reasons a proposal did not produce a state machine output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposeError {
+    /// This server is not the leader; `leader_id` is the current leader, if known
+    NotLeader { leader_id: Option<u64> },
+    /// The entry was overwritten by a new leader before it committed and will
+    /// never be applied; the command can safely be proposed again
+    Dropped,
+    /// No outcome was received in time; the command may or may not be applied
+    Timeout,
+    /// This server is handing its leadership over; the command can be proposed
+    /// again once the new leader is known
+    TransferringLeadership,
+}
+
+impl Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader { leader_id: Some(leader_id) } => {
+                write!(f, "not the leader, the leader is node {}", leader_id)
+            }
+            ProposeError::NotLeader { leader_id: None } => write!(f, "not the leader, the leader is unknown"),
+            ProposeError::Dropped => write!(f, "entry was overwritten by a new leader"),
+            ProposeError::Timeout => write!(f, "proposal timed out"),
+            ProposeError::TransferringLeadership => write!(f, "leadership is being transferred"),
+        }
+    }
+}
+
+impl Error for ProposeError {}
+
+/// How concurrent proposals are coalesced before being appended to the log
+///
+/// A batch is appended with a single storage write and sent to each follower
+/// in a single AppendEntry. It closes once it holds `max_batch_entries`
+/// commands or `max_batch_bytes` bytes of commands (the command reaching the
+/// limit included), or `max_linger` after its first proposal arrived; with no
+/// linger, only the proposals already waiting are coalesced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalBatching {
+    pub max_batch_entries: usize,   // Commands per batch, at least 1
+    pub max_batch_bytes: u64,       // Bytes of commands per batch
+    pub max_linger: Duration,       // Time the first proposal waits for others to join it
+}
+
+impl Default for ProposalBatching {
+    fn default() -> Self {
+        Self {
+            max_batch_entries: 64,
+            max_batch_bytes: 1024 * 1024,
+            max_linger: Duration::ZERO,
+        }
+    }
+}
+
+impl ProposalBatching {
+    /// Collects the proposals joining `first` in its batch
+    pub(crate) async fn collect(
+        &self,
+        first: Message<Proposal, ProposeResponse>,
+        receiver: &mut mpsc::Receiver<Message<Proposal, ProposeResponse>>,
+    ) -> Vec<Message<Proposal, ProposeResponse>> {
+        let deadline = Instant::now() + self.max_linger;
+        let mut batch_bytes = first.request.command.len() as u64;
+        let mut batch = vec![first];
+        while batch.len() < self.max_batch_entries && batch_bytes < self.max_batch_bytes {
+            // A proposal already waiting is taken even once the deadline passed
+            let Ok(Some(message)) = timeout_at(deadline, receiver.recv()).await else {
+                break;
+            };
+            batch_bytes += message.request.command.len() as u64;
+            batch.push(message);
+        }
+        batch
+    }
+}
+
+impl NodeCore {
+    /// Appends a batch of proposed commands to the leader's log
+    ///
+    /// The proposers are answered right away if this server is not the leader
+    /// or is handing its leadership over, otherwise each once its entry is
+    /// applied (see `apply_committed`).
+    pub(crate) fn propose(&mut self, batch: Vec<Message<Proposal, ProposeResponse>>) {
+        let (commands, response_channels): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|Message { request, response_channel }| (request.command, response_channel))
+            .unzip();
+        match self.append_batch(EntryKind::Command, commands) {
+            Some(positions) => {
+                for (position, response_channel) in positions.into_iter().zip(response_channels) {
+                    self.pending_responses.insert(position.index, (position.term, response_channel));
+                }
+            }
+            None => {
+                let result = if self.leadership_transfer.is_some() {
+                    Err(ProposeError::TransferringLeadership)
+                } else {
+                    Err(ProposeError::NotLeader { leader_id: self.leader_id })
+                };
+                for response_channel in response_channels {
+                    let _ = response_channel.send(ProposeResponse { result: result.clone() });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::network::RpcMessage;
+    use crate::node::node_core::tests::{elected_leader, new_core};
+    use crate::rpc::AppendEntryResponse;
+
+    fn proposal(command: Vec<u8>) -> (Message<Proposal, ProposeResponse>, oneshot::Receiver<ProposeResponse>) {
+        let (sender, receiver) = oneshot::channel();
+        (Message::new(Proposal { command }, sender), receiver)
+    }
+
+    #[test]
+    fn batch_is_sent_in_a_single_append_per_follower() {
+        let mut core = elected_leader(vec![2, 3]);
+        for peer in [2, 3] {
+            core.step(peer, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 1, ..Default::default() }));
+        }
+        core.take_outbox();
+
+        let (batch, _receivers): (Vec<_>, Vec<_>) = (1..=3u8).map(|command| proposal(vec![command])).unzip();
+        core.propose(batch);
+
+        let outbox = core.take_outbox();
+        assert_eq!(outbox.len(), 2);
+        for (_, message) in outbox {
+            match message {
+                RpcMessage::AppendEntry(request) => assert_eq!(request.entries.len(), 3),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        let mut pending: Vec<_> = core.pending_responses.keys().copied().collect();
+        pending.sort();
+        assert_eq!(pending, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn every_proposer_of_a_refused_batch_is_answered() {
+        let mut core = new_core(1, vec![2, 3]);
+        let (batch, receivers): (Vec<_>, Vec<_>) = (1..=2u8).map(|command| proposal(vec![command])).unzip();
+
+        core.propose(batch);
+
+        for mut receiver in receivers {
+            let response = receiver.try_recv().unwrap();
+            assert_eq!(response.result, Err(ProposeError::NotLeader { leader_id: None }));
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_closes_at_its_limits_or_after_the_linger_time() {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let batching = ProposalBatching {
+            max_batch_entries: 3,
+            max_batch_bytes: 4,
+            max_linger: Duration::ZERO,
+        };
+        let mut receivers = Vec::new();
+        for command in [vec![1], vec![2], vec![3], vec![4, 4, 4], vec![5]] {
+            let (message, receiver) = proposal(command);
+            sender.send(message).await.unwrap();
+            receivers.push(receiver);
+        }
+        let batch_commands = |batch: Vec<Message<Proposal, ProposeResponse>>| -> Vec<Vec<u8>> {
+            batch.into_iter().map(|message| message.request.command).collect()
+        };
+
+        // Three commands fill a batch, then four bytes do
+        let first = receiver.recv().await.unwrap();
+        assert_eq!(batch_commands(batching.collect(first, &mut receiver).await), vec![vec![1], vec![2], vec![3]]);
+        let first = receiver.recv().await.unwrap();
+        assert_eq!(batch_commands(batching.collect(first, &mut receiver).await), vec![vec![4, 4, 4], vec![5]]);
+
+        // A proposal arriving within the linger time joins the batch
+        let batching = ProposalBatching { max_linger: Duration::from_millis(200), ..batching };
+        let (message, _receiver) = proposal(vec![6]);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sender.send(message).await.unwrap();
+        });
+        let (first, _receiver) = proposal(vec![7]);
+        assert_eq!(batch_commands(batching.collect(first, &mut receiver).await), vec![vec![7], vec![6]]);
+    }
+}
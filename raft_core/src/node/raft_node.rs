@@ -0,0 +1,933 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use tokio::sync::{Notify, mpsc, oneshot};
+use tokio::task::spawn_blocking;
+use tokio::time::{Duration, Instant, interval, sleep, sleep_until, timeout};
+
+use super::config::{ConfigError, RaftConfig};
+use super::leadership::TransferError;
+use super::membership::{MembershipChange, MembershipError};
+use super::node_core::NodeCore;
+use super::proposal::{ProposalBatching, ProposeError, ProposeResponse, Proposal};
+use super::read::ReadError;
+use crate::channel::message::Message;
+use crate::channel::request_reply_channel::{Producer, RequestReplyChannel};
+use crate::configuration::Configuration;
+use crate::log::LogPosition;
+use crate::network::{ClusterInboundNetwork, ClusterOutboundNetwork, RpcMessage};
+use crate::state::State;
+use crate::state_machine::StateMachine;
+use crate::storage::raft_storage::{RaftStorage, StorageError};
+
+/// Interval at which the election and heartbeat timers are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of messages queued for a single peer before new ones are dropped
+///
+/// Raft tolerates message loss, so a slow or unreachable peer only costs it the
+/// messages that do not fit; they are re-sent by later heartbeats.
+const OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+/// Maximum number of proposals waiting to be appended to the log
+const PROPOSAL_QUEUE_SIZE: usize = 1024;
+
+/// Time a proposal may take from submission until its command is applied
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time a read may take until the state machine answers it
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time a membership change may take until the new configuration is committed,
+/// which includes bringing a new voter up to date
+const MEMBERSHIP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A Raft server
+///
+/// RaftNode owns the Raft state of this server and drives it: inbound RPCs are
+/// delivered through its `ClusterInboundNetwork` implementation, election and
+/// heartbeat timers run on a background task started by `start`, every message
+/// produced by the protocol is emitted through the `ClusterOutboundNetwork`, and
+/// committed entries are applied to the `StateMachine` as soon as they commit.
+pub struct RaftNode {
+    server_id: u64,
+    core: Mutex<NodeCore>,
+    network: Arc<dyn ClusterOutboundNetwork>,
+    peer_senders: Mutex<HashMap<u64, mpsc::Sender<RpcMessage>>>,
+    proposals: Producer<Proposal, ProposeResponse>,
+    proposal_receiver: Mutex<Option<mpsc::Receiver<Message<Proposal, ProposeResponse>>>>,
+    proposal_batching: ProposalBatching,
+    storage_sync: Arc<Notify>,   // Wakes the sync task when appended entries wait to be fsynced
+    running_signal: Arc<AtomicBool>,
+}
+
+impl RaftNode {
+    /// Creates a new RaftNode in the Follower state
+    ///
+    /// The persistent state (term, vote and log) is restored from `storage`.
+    ///
+    /// # Arguments
+    /// * `server_id` - Unique identifier for this server in the cluster
+    /// * `peers` - IDs of the other servers in the initial configuration, used
+    ///   until the log or snapshot holds a configuration
+    /// * `state_machine` - Application the committed entries are applied to
+    /// * `storage` - Stable storage for the persistent state
+    /// * `network` - Network used to send RPCs to the other servers
+    ///
+    /// # Returns
+    /// * `Err(StorageError)` if the persistent state could not be loaded
+    pub fn new(
+        server_id: u64,
+        peers: Vec<u64>,
+        state_machine: Box<dyn StateMachine>,
+        storage: Box<dyn RaftStorage>,
+        network: Arc<dyn ClusterOutboundNetwork>,
+    ) -> Result<Self, StorageError> {
+        let core = NodeCore::new(server_id, peers, state_machine, storage)?;
+        let (channel, proposal_receiver) = RequestReplyChannel::new(PROPOSAL_QUEUE_SIZE);
+        Ok(Self {
+            server_id,
+            core: Mutex::new(core),
+            network,
+            peer_senders: Mutex::new(HashMap::new()),
+            proposals: channel.new_producer(),
+            proposal_receiver: Mutex::new(Some(proposal_receiver)),
+            proposal_batching: ProposalBatching::default(),
+            storage_sync: Arc::new(Notify::new()),
+            running_signal: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Replaces the default `RaftConfig`, the timers and limits of this server
+    ///
+    /// Every setting goes through here, so that no combination is used without
+    /// being validated as a whole.
+    ///
+    /// # Returns
+    /// * `Err(ConfigError)` if `config` does not pass `RaftConfig::validate`
+    pub fn with_config(self, config: RaftConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        {
+            let mut core = self.core();
+            core.config = config;
+            // The election timer was armed with the default timeout range
+            core.reset_election_deadline();
+        }
+        Ok(self)
+    }
+
+    /// Replaces the default `ProposalBatching`, deciding how concurrent
+    /// proposals are coalesced into a single append
+    pub fn with_proposal_batching(mut self, proposal_batching: ProposalBatching) -> Self {
+        self.proposal_batching = proposal_batching;
+        self
+    }
+
+    /// Starts this server as a learner rather than a voter (disabled by default)
+    ///
+    /// Only applies while the log and snapshot hold no configuration, i.e. to
+    /// a new server joining a running cluster: it never campaigns, and receives
+    /// entries once the leader adds it with `add_learner`.
+    pub fn with_learner(self, enabled: bool) -> Self {
+        if enabled {
+            self.core().start_as_learner();
+        }
+        self
+    }
+
+    /// Starts the election and heartbeat timers, the storage sync timer and the
+    /// proposal loop on background tasks
+    ///
+    /// The tasks hold only a weak reference to the node and stop once the node
+    /// is dropped or `stop` is called.
+    pub fn start(self: &Arc<Self>) {
+        if self.running_signal.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let node = Arc::downgrade(self);
+        let running_signal = self.running_signal.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(TICK_INTERVAL);
+            while running_signal.load(Ordering::Acquire) {
+                ticker.tick().await;
+                let Some(node) = node.upgrade() else {
+                    break;
+                };
+                node.tick();
+            }
+        });
+
+        let node = Arc::downgrade(self);
+        let running_signal = self.running_signal.clone();
+        let storage_sync = self.storage_sync.clone();
+        tokio::spawn(async move {
+            while running_signal.load(Ordering::Acquire) {
+                if timeout(Duration::from_millis(500), storage_sync.notified()).await.is_err() {
+                    continue;
+                }
+                // Entries appended while waiting may bring the deadline forward,
+                // e.g. once enough of them wait for a group commit
+                let due = loop {
+                    let Some(deadline) = node.upgrade().and_then(|node| node.core().storage.sync_deadline()) else {
+                        break false;
+                    };
+                    tokio::select! {
+                        _ = sleep_until(Instant::from_std(deadline)) => break true,
+                        _ = storage_sync.notified() => {}
+                    }
+                };
+                if !due {
+                    continue;
+                }
+                let Some(node) = node.upgrade() else {
+                    break;
+                };
+                let Some(sync) = node.drive(NodeCore::start_storage_sync) else {
+                    continue;
+                };
+                // The fsync runs on a blocking thread, without the core lock
+                let result = spawn_blocking(move || sync.run())
+                    .await
+                    .unwrap_or_else(|error| Err(StorageError::Io(io::Error::other(error))));
+                node.drive(|core| core.finish_storage_sync(result));
+            }
+        });
+
+        let Some(mut receiver) = self.proposal_receiver.lock().unwrap().take() else {
+            return;
+        };
+        let node = Arc::downgrade(self);
+        let running_signal = self.running_signal.clone();
+        let proposal_batching = self.proposal_batching.clone();
+        tokio::spawn(async move {
+            while running_signal.load(Ordering::Acquire) {
+                match timeout(Duration::from_millis(500), receiver.recv()).await {
+                    Ok(Some(message)) => {
+                        let batch = proposal_batching.collect(message, &mut receiver).await;
+                        let Some(node) = node.upgrade() else {
+                            break;
+                        };
+                        node.drive(|core| core.propose(batch));
+                    }
+                    Ok(None) => break,
+                    Err(_elapsed) => continue,
+                }
+            }
+        });
+    }
+
+    /// Stops the background tasks; inbound messages are still processed
+    ///
+    /// A stopped node cannot be started again.
+    pub fn stop(&self) {
+        self.running_signal.store(false, Ordering::Release);
+    }
+
+    /// Returns the unique identifier of this server
+    pub fn server_id(&self) -> u64 {
+        self.server_id
+    }
+
+    /// Returns the current role of this server
+    pub fn state(&self) -> State {
+        self.core().state.state.clone()
+    }
+
+    /// Returns the latest term this server has seen
+    pub fn current_term(&self) -> u64 {
+        self.core().state.current_term
+    }
+
+    /// Returns the leader of the current term, if known
+    pub fn leader_id(&self) -> Option<u64> {
+        self.core().leader_id
+    }
+
+    /// Returns the latest cluster configuration known to this server, which may
+    /// not be committed yet
+    pub fn configuration(&self) -> Configuration {
+        self.core().configuration.clone()
+    }
+
+    /// Returns the position of the highest log entry known to be committed
+    pub fn commit_position(&self) -> LogPosition {
+        self.core().state.commit_position.clone()
+    }
+
+    /// Returns the position of the highest log entry applied to the state machine
+    pub fn last_applied(&self) -> LogPosition {
+        self.core().state.last_applied.clone()
+    }
+
+    /// Submits a command to the cluster
+    ///
+    /// The command is appended to the leader's log and replicated; the returned
+    /// future resolves once the command is committed and applied to the state
+    /// machine of this server.
+    ///
+    /// # Returns
+    /// * `Ok(response)` - The state machine's output for the command
+    /// * `Err(ProposeError::NotLeader)` - This server is not the leader
+    /// * `Err(ProposeError::Dropped)` - The command was discarded by a new leader
+    /// * `Err(ProposeError::Timeout)` - The command did not complete in time
+    pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>, ProposeError> {
+        match self.proposals.send(Proposal { command }, PROPOSE_TIMEOUT).await {
+            Ok(response) => response.result,
+            Err(_) => Err(ProposeError::Timeout),
+        }
+    }
+
+    /// Queries the state machine with linearizable semantics
+    ///
+    /// The query is not written to the log: the leader confirms it still leads
+    /// the cluster with a round of heartbeats, then answers once every entry
+    /// committed when the read was received is applied, so the answer reflects
+    /// every write that completed before the read started. A follower asks the
+    /// leader for that commit index and answers from its own state machine.
+    ///
+    /// # Returns
+    /// * `Ok(response)` - The state machine's answer to the query
+    /// * `Err(ReadError::NotLeader)` - The leader is unknown
+    /// * `Err(ReadError::NotReady)` - The leader cannot serve reads yet, e.g. it
+    ///   was just elected and its first entry, a no-op, has not committed yet
+    /// * `Err(ReadError::Timeout)` - The read did not complete in time
+    pub async fn read(&self, query: Vec<u8>) -> Result<Vec<u8>, ReadError> {
+        let (sender, receiver) = oneshot::channel();
+        self.drive(|core| core.read(query, sender));
+        match timeout(READ_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(ReadError::Timeout),
+        }
+    }
+
+    /// Hands the leadership of this server over to `target_id`
+    ///
+    /// Proposals are refused while the target catches up with the leader's log;
+    /// it is then told to start an election right away, so the cluster is not
+    /// left without a leader for a whole election timeout, e.g. when this
+    /// server is about to be restarted.
+    ///
+    /// # Returns
+    /// * `Ok(())` - `target_id` is the leader now (or already was this server)
+    /// * `Err(TransferError::NotLeader)` - This server is not the leader
+    /// * `Err(TransferError::UnknownPeer)` - `target_id` is not a voter of the cluster
+    /// * `Err(TransferError::MembershipChangeInProgress)` - A membership change has not completed yet
+    /// * `Err(TransferError::Timeout)` - The target did not take over in time;
+    ///   this server remains the leader
+    /// * `Err(TransferError::LeadershipLost)` - This server stopped leading, but
+    ///   another server than the target took over, or none within an election timeout
+    pub async fn transfer_leadership(&self, target_id: u64) -> Result<(), TransferError> {
+        if target_id == self.server_id {
+            return match self.core().state.state {
+                State::Leader => Ok(()),
+                _ => Err(TransferError::NotLeader { leader_id: self.leader_id() }),
+            };
+        }
+
+        self.drive(|core| core.transfer_leadership(target_id))?;
+        let mut stepped_down_at = None;
+        loop {
+            sleep(TICK_INTERVAL).await;
+            let core = self.core();
+            if core.state.state == State::Leader {
+                if core.leadership_transfer.is_none() {
+                    return Err(TransferError::Timeout);
+                }
+                continue;
+            }
+            match core.leader_id {
+                Some(leader_id) if leader_id == target_id => return Ok(()),
+                Some(leader_id) => return Err(TransferError::LeadershipLost { leader_id: Some(leader_id) }),
+                // The target's vote request deposed this server; its election is under way
+                None => {
+                    let stepped_down_at = *stepped_down_at.get_or_insert_with(Instant::now);
+                    if stepped_down_at.elapsed() > core.config.election_timeout_max {
+                        return Err(TransferError::LeadershipLost { leader_id: None });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds `node_id` to the voters of the cluster, or promotes the learner `node_id`
+    ///
+    /// The change goes through a joint configuration of the old and new voters,
+    /// so the cluster keeps serving requests meanwhile. The new server must be
+    /// reachable through the network; it is brought up to date by the leader
+    /// and starts voting once the joint configuration is committed. Adding it
+    /// as a learner first keeps the cluster available while it catches up.
+    ///
+    /// # Returns
+    /// * `Ok(())` - The new configuration is committed (or `node_id` already was a voter)
+    /// * `Err(MembershipError::NotLeader)` - This server is not the leader
+    /// * `Err(MembershipError::ChangeInProgress)` - Another change has not completed yet
+    /// * `Err(MembershipError::LearnerCatchingUp)` - The learner has not caught up yet
+    /// * `Err(MembershipError::Timeout)` - The change did not complete in time
+    pub async fn add_voter(&self, node_id: u64) -> Result<(), MembershipError> {
+        self.change_membership(MembershipChange::AddVoter(node_id)).await
+    }
+
+    /// Adds `node_id` to the cluster as a learner
+    ///
+    /// A learner is sent every entry, or the snapshot if it lags behind, but
+    /// does not vote, so it can catch up without affecting the availability of
+    /// the cluster. Once it has, `add_voter` promotes it.
+    ///
+    /// # Returns
+    /// * `Ok(())` - The new configuration is committed (or `node_id` already was a member)
+    /// * `Err(MembershipError::NotLeader)` - This server is not the leader
+    /// * `Err(MembershipError::ChangeInProgress)` - Another change has not completed yet
+    /// * `Err(MembershipError::Timeout)` - The change did not complete in time
+    pub async fn add_learner(&self, node_id: u64) -> Result<(), MembershipError> {
+        self.change_membership(MembershipChange::AddLearner(node_id)).await
+    }
+
+    /// Removes the learner `node_id` from the cluster
+    ///
+    /// # Returns
+    /// * `Ok(())` - The new configuration is committed (or `node_id` was not a learner)
+    /// * `Err(MembershipError::NotLeader)` - This server is not the leader
+    /// * `Err(MembershipError::ChangeInProgress)` - Another change has not completed yet
+    /// * `Err(MembershipError::Timeout)` - The change did not complete in time
+    pub async fn remove_learner(&self, node_id: u64) -> Result<(), MembershipError> {
+        self.change_membership(MembershipChange::RemoveLearner(node_id)).await
+    }
+
+    /// Removes `node_id` from the voters of the cluster
+    ///
+    /// Like `add_voter`, the change goes through a joint configuration. A
+    /// leader removing itself steps down once the new configuration commits.
+    ///
+    /// # Returns
+    /// * `Ok(())` - The new configuration is committed (or `node_id` was not a voter)
+    /// * `Err(MembershipError::NotLeader)` - This server is not the leader
+    /// * `Err(MembershipError::ChangeInProgress)` - Another change has not completed yet
+    /// * `Err(MembershipError::NoVoters)` - `node_id` is the last voter
+    /// * `Err(MembershipError::Timeout)` - The change did not complete in time
+    pub async fn remove_voter(&self, node_id: u64) -> Result<(), MembershipError> {
+        self.change_membership(MembershipChange::RemoveVoter(node_id)).await
+    }
+
+    async fn change_membership(&self, change: MembershipChange) -> Result<(), MembershipError> {
+        let (sender, receiver) = oneshot::channel();
+        self.drive(|core| core.change_membership(change, sender));
+        match timeout(MEMBERSHIP_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(MembershipError::Timeout),
+        }
+    }
+
+    fn tick(&self) {
+        self.drive(NodeCore::tick);
+    }
+
+    fn core(&self) -> MutexGuard<'_, NodeCore> {
+        self.core.lock().unwrap()
+    }
+
+    /// Runs `action` on the core, then applies newly committed entries, sends
+    /// the messages the core produced and wakes the storage sync task if
+    /// appended entries wait to be fsynced
+    ///
+    /// The messages leave before the sync, which the sync task runs without
+    /// the core lock, so the leader's followers store its new entries while
+    /// its own copy is fsynced.
+    fn drive<R>(&self, action: impl FnOnce(&mut NodeCore) -> R) -> R {
+        let (result, messages, sync_deadline) = {
+            let mut core = self.core();
+            let result = action(&mut core);
+            core.apply_committed();
+            (result, core.take_outbox(), core.storage.sync_deadline())
+        };
+        self.dispatch(messages);
+        if sync_deadline.is_some() {
+            self.storage_sync.notify_one();
+        }
+        result
+    }
+
+    /// Hands messages to the per-peer sender tasks
+    ///
+    /// Each peer gets its own task so that messages to a peer keep their order
+    /// and a slow peer never delays messages to the others.
+    fn dispatch(&self, messages: Vec<(u64, RpcMessage)>) {
+        if messages.is_empty() {
+            return;
+        }
+
+        let mut peer_senders = self.peer_senders.lock().unwrap();
+        for (node_id, message) in messages {
+            let sender = peer_senders
+                .entry(node_id)
+                .or_insert_with(|| self.spawn_peer_sender(node_id));
+            if let Err(error) = sender.try_send(message) {
+                log::debug!("Dropping message to node {}: {}", node_id, error);
+            }
+        }
+    }
+
+    fn spawn_peer_sender(&self, node_id: u64) -> mpsc::Sender<RpcMessage> {
+        let (sender, mut receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let network = self.network.clone();
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if let Err(error) = network.send(message, node_id).await {
+                    log::debug!("Failed to send message to node {}: {}", node_id, error);
+                }
+            }
+        });
+        sender
+    }
+}
+
+#[async_trait]
+impl ClusterInboundNetwork for RaftNode {
+    async fn receive(&self, message: RpcMessage, node_id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.drive(|core| core.step(node_id, message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{RwLock, Weak};
+
+    use super::*;
+    use crate::node::read::ReadMode;
+    use crate::node::snapshot::SnapshotPolicy;
+    use crate::state_machine::tests::EchoStateMachine;
+    use crate::storage::file_storage::FileStorage;
+    use crate::storage::memory_storage::MemoryStorage;
+    use crate::storage::wal::{DurabilityPolicy, WalOptions};
+
+    /// In-process network delivering messages straight to the target node
+    #[derive(Default)]
+    struct LocalNetwork {
+        nodes: RwLock<HashMap<u64, Weak<RaftNode>>>,
+        disconnected: RwLock<HashSet<u64>>,   // Nodes that can neither send nor receive
+    }
+
+    /// The outbound side of `LocalNetwork` for a single node
+    struct LocalSender {
+        from: u64,
+        network: Arc<LocalNetwork>,
+    }
+
+    #[async_trait]
+    impl ClusterOutboundNetwork for LocalSender {
+        async fn send(&self, message: RpcMessage, node_id: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let reachable = {
+                let disconnected = self.network.disconnected.read().unwrap();
+                !disconnected.contains(&self.from) && !disconnected.contains(&node_id)
+            };
+            if !reachable {
+                return Err(format!("node {} is unreachable", node_id).into());
+            }
+            let node = self.network.nodes.read().unwrap().get(&node_id).and_then(Weak::upgrade);
+            match node {
+                Some(node) => node.receive(message, self.from).await,
+                None => Err(format!("node {} is down", node_id).into()),
+            }
+        }
+    }
+
+    fn start_cluster(ids: &[u64]) -> Vec<Arc<RaftNode>> {
+        start_cluster_with(ids, |_| Box::new(MemoryStorage::new()), |node| node).1
+    }
+
+    /// Starts a cluster whose nodes use the given storage, letting `configure`
+    /// adjust every node before it starts
+    fn start_cluster_with(
+        ids: &[u64],
+        storage: impl Fn(u64) -> Box<dyn RaftStorage>,
+        configure: impl Fn(RaftNode) -> RaftNode,
+    ) -> (Arc<LocalNetwork>, Vec<Arc<RaftNode>>) {
+        let network = Arc::new(LocalNetwork::default());
+        let nodes: Vec<Arc<RaftNode>> = ids
+            .iter()
+            .map(|id| {
+                let peers = ids.iter().copied().filter(|peer| peer != id).collect();
+                let state_machine = Box::new(EchoStateMachine::default());
+                let sender = Arc::new(LocalSender { from: *id, network: network.clone() });
+                let node = RaftNode::new(*id, peers, state_machine, storage(*id), sender).unwrap();
+                Arc::new(configure(node))
+            })
+            .collect();
+        for node in &nodes {
+            network.nodes.write().unwrap().insert(node.server_id(), Arc::downgrade(node));
+            node.start();
+        }
+        (network, nodes)
+    }
+
+    /// Starts a node joining the cluster of `network`, knowing `peers` from the start
+    fn start_joining_node(
+        network: &Arc<LocalNetwork>,
+        id: u64,
+        peers: Vec<u64>,
+        configure: impl FnOnce(RaftNode) -> RaftNode,
+    ) -> Arc<RaftNode> {
+        let state_machine = Box::new(EchoStateMachine::default());
+        let sender = Arc::new(LocalSender { from: id, network: network.clone() });
+        let storage = Box::new(MemoryStorage::new());
+        let node = RaftNode::new(id, peers, state_machine, storage, sender).unwrap();
+        let node = node.with_config(RaftConfig { pre_vote: true, ..RaftConfig::default() }).unwrap();
+        let node = Arc::new(configure(node));
+        network.nodes.write().unwrap().insert(id, Arc::downgrade(&node));
+        node.start();
+        node
+    }
+
+    async fn wait_for_leader(nodes: &[Arc<RaftNode>]) -> u64 {
+        for _ in 0..200 {
+            let leaders: Vec<u64> = nodes
+                .iter()
+                .filter(|node| node.state() == State::Leader)
+                .map(|node| node.server_id())
+                .collect();
+            if leaders.len() == 1 {
+                return leaders[0];
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader was elected");
+    }
+
+    fn node(nodes: &[Arc<RaftNode>], server_id: u64) -> &Arc<RaftNode> {
+        nodes.iter().find(|node| node.server_id() == server_id).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cluster_elects_a_single_leader() {
+        let nodes = start_cluster(&[1, 2, 3]);
+
+        let leader_id = wait_for_leader(&nodes).await;
+        // Let a few heartbeats go by so every follower has learned about the leader
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let leader_term = node(&nodes, leader_id).current_term();
+        for node in &nodes {
+            assert_eq!(node.leader_id(), Some(leader_id));
+            assert_eq!(node.current_term(), leader_term);
+        }
+    }
+
+    #[tokio::test]
+    async fn committed_entries_are_applied_on_every_node() {
+        let nodes = start_cluster(&[1, 2, 3]);
+        let leader = node(&nodes, wait_for_leader(&nodes).await).clone();
+
+        let proposals = (0..100u8).map(|i| leader.propose(vec![i]));
+        let responses = futures::future::join_all(proposals).await;
+
+        // Each proposer gets the state machine output of its own command back
+        for (i, response) in responses.into_iter().enumerate() {
+            assert_eq!(response, Ok(vec![i as u8]));
+        }
+        let last = leader.last_applied();
+        for _ in 0..200 {
+            if nodes.iter().all(|node| node.last_applied() == last) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("entries were not applied on every node");
+    }
+
+    #[tokio::test]
+    async fn leader_syncs_its_entries_right_after_sending_them() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_, nodes) = start_cluster_with(&[1, 2, 3], |id| {
+            Box::new(FileStorage::open(directory.path().join(id.to_string())).unwrap())
+        }, |node| node);
+        let leader = node(&nodes, wait_for_leader(&nodes).await).clone();
+
+        assert_eq!(leader.propose(vec![1]).await, Ok(vec![1]));
+        // The two followers commit the entry without waiting for the leader's fsync
+        for _ in 0..100 {
+            let synced = {
+                let core = leader.core();
+                core.storage.durable_index() == core.storage.last_index() && core.storage.sync_deadline().is_none()
+            };
+            if synced {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the leader did not sync its entries");
+    }
+
+    #[tokio::test]
+    async fn group_committed_proposals_complete_after_the_sync_delay() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_, nodes) = start_cluster_with(&[1, 2, 3], |id| {
+            let options = WalOptions {
+                durability: DurabilityPolicy::GroupCommit {
+                    max_entries: 1000,
+                    max_delay: Duration::from_millis(2),
+                },
+                ..WalOptions::default()
+            };
+            Box::new(FileStorage::open_with_options(directory.path().join(id.to_string()), options).unwrap())
+        }, |node| node);
+        let leader = node(&nodes, wait_for_leader(&nodes).await).clone();
+
+        // Far fewer entries than max_entries: only the delay makes them durable
+        assert_eq!(leader.propose(vec![1]).await, Ok(vec![1]));
+        assert_eq!(leader.propose(vec![2]).await, Ok(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn group_commit_syncs_the_leader_once_enough_entries_wait() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_, nodes) = start_cluster_with(&[1, 2, 3], |id| {
+            let options = WalOptions {
+                durability: DurabilityPolicy::GroupCommit {
+                    max_entries: 2,
+                    max_delay: Duration::from_secs(60),
+                },
+                ..WalOptions::default()
+            };
+            Box::new(FileStorage::open_with_options(directory.path().join(id.to_string()), options).unwrap())
+        }, |node| node);
+        let leader = node(&nodes, wait_for_leader(&nodes).await).clone();
+
+        // The no-op and this command fill the group commit long before its delay
+        assert_eq!(leader.propose(vec![1]).await, Ok(vec![1]));
+        for _ in 0..100 {
+            let synced = {
+                let core = leader.core();
+                core.storage.durable_index() == core.storage.last_index()
+            };
+            if synced {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the leader waited for the group commit delay");
+    }
+
+    #[tokio::test]
+    async fn follower_offline_during_compaction_catches_up_from_a_snapshot() {
+        let snapshot_policy = SnapshotPolicy { max_applied_entries: Some(10), max_log_bytes: None };
+        let (network, nodes) = start_cluster_with(
+            &[1, 2, 3],
+            |_| Box::new(MemoryStorage::new()),
+            // Without PreVote the follower would come back with a higher term and depose the leader
+            |node| {
+                let config = RaftConfig { pre_vote: true, snapshot_policy: snapshot_policy.clone(), ..RaftConfig::default() };
+                node.with_config(config).unwrap()
+            },
+        );
+        let leader = node(&nodes, wait_for_leader(&nodes).await).clone();
+        let offline = nodes.iter().find(|node| node.server_id() != leader.server_id()).unwrap();
+
+        network.disconnected.write().unwrap().insert(offline.server_id());
+        for i in 0..30u8 {
+            assert_eq!(leader.propose(vec![i]).await, Ok(vec![i]));
+        }
+        // Let the heartbeats carry the commit position to the other follower
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        network.disconnected.write().unwrap().clear();
+
+        for _ in 0..300 {
+            let leader_applied = nodes.iter().find(|node| node.state() == State::Leader).map(|node| node.last_applied());
+            if let Some(leader_applied) = leader_applied
+                && leader_applied.index > 30
+                && nodes.iter().all(|node| node.last_applied() == leader_applied)
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the offline follower did not catch up");
+    }
+
+    #[tokio::test]
+    async fn rejoining_node_does_not_depose_the_leader_with_pre_vote() {
+        let (network, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+            node.with_config(RaftConfig { pre_vote: true, ..RaftConfig::default() }).unwrap()
+        });
+        let leader_id = wait_for_leader(&nodes).await;
+        let leader_term = node(&nodes, leader_id).current_term();
+        let isolated = nodes.iter().find(|node| node.server_id() != leader_id).unwrap();
+
+        // Long enough for the isolated node to time out several times
+        network.disconnected.write().unwrap().insert(isolated.server_id());
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert_eq!(isolated.current_term(), leader_term);
+        network.disconnected.write().unwrap().clear();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(node(&nodes, leader_id).state(), State::Leader);
+        assert_eq!(node(&nodes, leader_id).current_term(), leader_term);
+        assert_eq!(isolated.leader_id(), Some(leader_id));
+    }
+
+    #[tokio::test]
+    async fn partitioned_leader_steps_down() {
+        let (network, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| node);
+        let leader_id = wait_for_leader(&nodes).await;
+
+        network.disconnected.write().unwrap().insert(leader_id);
+        tokio::time::sleep(Duration::from_millis(700)).await;
+
+        assert_ne!(node(&nodes, leader_id).state(), State::Leader);
+        let old_leader = node(&nodes, leader_id);
+        assert_eq!(
+            old_leader.propose(vec![1]).await,
+            Err(ProposeError::NotLeader { leader_id: None })
+        );
+    }
+
+    #[tokio::test]
+    async fn leadership_is_handed_over_to_the_target() {
+        let (_, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+            node.with_config(RaftConfig { pre_vote: true, ..RaftConfig::default() }).unwrap()
+        });
+        let leader_id = wait_for_leader(&nodes).await;
+        let leader = node(&nodes, leader_id);
+        leader.propose(vec![1]).await.unwrap();
+        let target_id = nodes.iter().map(|node| node.server_id()).find(|id| *id != leader_id).unwrap();
+
+        leader.transfer_leadership(target_id).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(node(&nodes, target_id).state(), State::Leader);
+        assert_eq!(leader.leader_id(), Some(target_id));
+        assert_eq!(node(&nodes, target_id).propose(vec![2]).await, Ok(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn transfer_fails_when_another_node_takes_over() {
+        let (network, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+            node.with_config(RaftConfig { pre_vote: true, ..RaftConfig::default() }).unwrap()
+        });
+        let leader_id = wait_for_leader(&nodes).await;
+        let leader = node(&nodes, leader_id).clone();
+        leader.propose(vec![1]).await.unwrap();
+        let mut others = nodes.iter().map(|node| node.server_id()).filter(|id| *id != leader_id);
+        let (target_id, other_id) = (others.next().unwrap(), others.next().unwrap());
+        // The target never receives TimeoutNow
+        network.disconnected.write().unwrap().insert(target_id);
+
+        let transfer = tokio::spawn({
+            let leader = leader.clone();
+            async move { leader.transfer_leadership(target_id).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        leader.drive(|core| {
+            let term = core.state.current_term + 1;
+            core.become_follower(term, Some(other_id))
+        });
+
+        assert_eq!(transfer.await.unwrap(), Err(TransferError::LeadershipLost { leader_id: Some(other_id) }));
+    }
+
+    #[tokio::test]
+    async fn reads_see_completed_writes_on_every_node() {
+        let nodes = start_cluster(&[1, 2, 3]);
+        let leader_id = wait_for_leader(&nodes).await;
+        let leader = node(&nodes, leader_id);
+
+        leader.propose(vec![1]).await.unwrap();
+        assert_eq!(leader.read(vec![]).await, Ok(vec![1]));
+        leader.propose(vec![2]).await.unwrap();
+        assert_eq!(leader.read(vec![]).await, Ok(vec![2]));
+
+        for follower in nodes.iter().filter(|node| node.server_id() != leader_id) {
+            assert_eq!(follower.read(vec![]).await, Ok(vec![2]));
+        }
+    }
+
+    #[tokio::test]
+    async fn lease_reads_see_completed_writes() {
+        let (_, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+            let read_mode = ReadMode::Lease { max_clock_drift: Duration::from_millis(10) };
+            node.with_config(RaftConfig { read_mode, ..RaftConfig::default() }).unwrap()
+        });
+        let leader = node(&nodes, wait_for_leader(&nodes).await);
+
+        for command in 1..=3u8 {
+            leader.propose(vec![command]).await.unwrap();
+            assert_eq!(leader.read(vec![]).await, Ok(vec![command]));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_voter_is_replaced_without_downtime() {
+        let (network, nodes) =
+            start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+                node.with_config(RaftConfig { pre_vote: true, ..RaftConfig::default() }).unwrap()
+            });
+        let leader_id = wait_for_leader(&nodes).await;
+        let leader = node(&nodes, leader_id);
+        leader.propose(vec![1]).await.unwrap();
+
+        let failed_id = nodes.iter().map(|node| node.server_id()).find(|id| *id != leader_id).unwrap();
+        network.disconnected.write().unwrap().insert(failed_id);
+        let replacement = start_joining_node(&network, 4, vec![1, 2, 3], |node| node);
+
+        assert_eq!(leader.add_voter(4).await, Ok(()));
+        assert_eq!(leader.remove_voter(failed_id).await, Ok(()));
+        let voters = [1, 2, 3, 4].into_iter().filter(|id| *id != failed_id);
+        assert_eq!(leader.configuration(), Configuration::new(voters));
+
+        assert_eq!(leader.propose(vec![2]).await, Ok(vec![2]));
+        for _ in 0..100 {
+            if replacement.last_applied() == leader.last_applied() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(replacement.last_applied(), leader.last_applied());
+        assert_eq!(replacement.configuration(), leader.configuration());
+    }
+
+    #[tokio::test]
+    async fn learner_catches_up_from_a_snapshot_before_its_promotion() {
+        let snapshot_policy = SnapshotPolicy { max_applied_entries: Some(2), max_log_bytes: None };
+        let (network, nodes) = start_cluster_with(&[1, 2, 3], |_| Box::new(MemoryStorage::new()), |node| {
+            let config = RaftConfig { pre_vote: true, snapshot_policy: snapshot_policy.clone(), ..RaftConfig::default() };
+            node.with_config(config).unwrap()
+        });
+        let leader = node(&nodes, wait_for_leader(&nodes).await);
+        for command in 1..=5u8 {
+            leader.propose(vec![command]).await.unwrap();
+        }
+
+        let learner = start_joining_node(&network, 4, vec![1, 2, 3], |node| node.with_learner(true));
+        assert_eq!(leader.add_learner(4).await, Ok(()));
+        for _ in 0..100 {
+            if learner.last_applied() == leader.last_applied() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(learner.last_applied(), leader.last_applied());
+        assert_eq!(learner.state(), State::Follower);
+
+        assert_eq!(leader.add_voter(4).await, Ok(()));
+        assert_eq!(leader.configuration(), Configuration::new([1, 2, 3, 4]));
+        assert_eq!(leader.propose(vec![6]).await, Ok(vec![6]));
+    }
+
+    #[tokio::test]
+    async fn followers_refuse_proposals() {
+        let nodes = start_cluster(&[1, 2, 3]);
+        let leader_id = wait_for_leader(&nodes).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let follower = nodes.iter().find(|node| node.server_id() != leader_id).unwrap();
+        assert_eq!(
+            follower.propose(vec![1]).await,
+            Err(ProposeError::NotLeader { leader_id: Some(leader_id) })
+        );
+    }
+}
@@ -0,0 +1,563 @@
+//! Linearizable reads through the ReadIndex protocol: the leader records its
+//! commit position, confirms it is still the leader with a round of heartbeats,
+//! and queries the state machine once that position is applied, without
+//! appending anything to the log. In lease mode, a recent heartbeat round
+//! stands in for the confirmation.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+use super::node_core::NodeCore;
+use crate::network::RpcMessage;
+use crate::rpc::{ReadIndexRequest, ReadIndexResponse};
+use crate::state::State;
+
+/// How the leader makes sure it still leads the cluster before answering a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Every read waits for a round of heartbeats to be acknowledged by a majority
+    #[default]
+    ReadIndex,
+    /// Reads are answered locally while the leader holds a lease
+    ///
+    /// Once a majority acknowledged a heartbeat, none of them votes for another
+    /// candidate within the minimum election timeout (CheckQuorum), so the
+    /// leader holds a lease for that long after sending it, shortened by
+    /// `max_clock_drift`: the most the clocks of two servers may drift apart
+    /// over that period. Reads are only as safe as this bound; while the lease
+    /// is expired, reads use ReadIndex. Requires CheckQuorum, and a drift below
+    /// the minimum election timeout (see `RaftConfig::validate`).
+    Lease { max_clock_drift: Duration },
+}
+
+/// Reasons a read did not produce a state machine answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadError {
+    /// This server is not the leader; `leader_id` is the current leader, if known
+    NotLeader { leader_id: Option<u64> },
+    /// The leader cannot serve reads yet, typically because it has not
+    /// committed an entry of its term and does not know the commit position of
+    /// the cluster; the read can be retried shortly
+    NotReady,
+    /// No answer was received in time
+    Timeout,
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::NotLeader { leader_id: Some(leader_id) } => {
+                write!(f, "not the leader, the leader is node {}", leader_id)
+            }
+            ReadError::NotLeader { leader_id: None } => write!(f, "not the leader, the leader is unknown"),
+            ReadError::NotReady => write!(f, "leader cannot serve reads yet"),
+            ReadError::Timeout => write!(f, "read timed out"),
+        }
+    }
+}
+
+impl Error for ReadError {}
+
+/// A read waiting for the leadership to be confirmed and its read index to be
+/// applied
+pub(crate) struct PendingRead {
+    pub(crate) id: u64,           // Heartbeat round that must be acknowledged by a majority
+    pub(crate) read_index: u64,   // Commit index when the read was received by the leader
+    pub(crate) confirmed: bool,   // Whether a majority acknowledged the round
+    pub(crate) requester: ReadRequester,
+}
+
+/// Who a pending read is answered to
+pub(crate) enum ReadRequester {
+    /// A client of this server, answered with the state machine's output
+    Client {
+        query: Vec<u8>,
+        response_channel: oneshot::Sender<Result<Vec<u8>, ReadError>>,
+    },
+    /// A follower serving a read, answered with the read index
+    Follower { node_id: u64, request_id: u64 },
+}
+
+/// A read a follower forwarded to the leader to learn its read index
+pub(crate) struct ForwardedRead {
+    pub(crate) term: u64,      // Term the read was forwarded in
+    pub(crate) leader_id: u64, // Leader the read was forwarded to
+    pub(crate) query: Vec<u8>,
+    pub(crate) response_channel: oneshot::Sender<Result<Vec<u8>, ReadError>>,
+}
+
+impl NodeCore {
+    /// Starts a linearizable read of the state machine
+    ///
+    /// On the leader, the read is answered once a majority acknowledged a
+    /// heartbeat sent after it was received, which proves no other leader could
+    /// have committed entries past the recorded commit index, and once that
+    /// index is applied. A follower asks the leader for such a read index, then
+    /// answers the read itself once it applied that index.
+    pub(crate) fn read(&mut self, query: Vec<u8>, response_channel: oneshot::Sender<Result<Vec<u8>, ReadError>>) {
+        if self.state.state != State::Leader {
+            self.forward_read(query, response_channel);
+            return;
+        }
+        let Some(read_index) = self.read_index() else {
+            let _ = response_channel.send(Err(ReadError::NotReady));
+            return;
+        };
+        self.start_read(read_index, ReadRequester::Client { query, response_channel });
+    }
+
+    /// Returns the index a read received now must wait for, if the leader knows it
+    ///
+    /// Entries of earlier terms may be committed without the leader knowing it
+    /// until an entry of its own term commits (Raft paper, section 8).
+    fn read_index(&self) -> Option<u64> {
+        let commit_index = self.state.commit_position.index;
+        (self.state.term_at(commit_index) == Some(self.state.current_term)).then_some(commit_index)
+    }
+
+    /// Queues a read on the leader and starts a heartbeat round to confirm it,
+    /// unless the lease already does
+    fn start_read(&mut self, read_index: u64, requester: ReadRequester) {
+        let leased = self.holds_lease(Instant::now());
+        self.pending_reads.push_back(PendingRead {
+            id: self.read_sequence + 1,
+            read_index,
+            confirmed: leased || self.configuration.has_majority(|id| id == self.state.server_id),
+            requester,
+        });
+        if !leased {
+            self.broadcast_append();
+        }
+        self.serve_reads();
+    }
+
+    /// Asks the leader for a read index on behalf of a client of this follower
+    fn forward_read(&mut self, query: Vec<u8>, response_channel: oneshot::Sender<Result<Vec<u8>, ReadError>>) {
+        let Some(leader_id) = self.leader_id else {
+            let _ = response_channel.send(Err(ReadError::NotLeader { leader_id: None }));
+            return;
+        };
+        // Reads whose client gave up waiting are not answered by the leader anymore
+        self.forwarded_reads.retain(|_, read| !read.response_channel.is_closed());
+
+        self.forwarded_read_sequence += 1;
+        let request_id = self.forwarded_read_sequence;
+        let read = ForwardedRead {
+            term: self.state.current_term,
+            leader_id,
+            query,
+            response_channel,
+        };
+        self.forwarded_reads.insert(request_id, read);
+        let request = ReadIndexRequest {
+            current_term: self.state.current_term,
+            request_id,
+        };
+        self.send(leader_id, RpcMessage::ReadIndex(request));
+    }
+
+    /// Confirms the leadership for a follower's read and answers with the read index
+    pub(crate) fn handle_read_index(&mut self, from: u64, request: ReadIndexRequest) {
+        let read_index = if self.state.state == State::Leader { self.read_index() } else { None };
+        let requester = ReadRequester::Follower { node_id: from, request_id: request.request_id };
+        match read_index {
+            Some(read_index) => self.start_read(read_index, requester),
+            None => self.reply_read_index(requester, None),
+        }
+    }
+
+    /// Waits for the read index the leader answered to be applied, then
+    /// answers the forwarded read
+    ///
+    /// Only the leader the read was forwarded to, in the same term, can
+    /// answer it; other answers are stale or misdirected and are dropped.
+    pub(crate) fn handle_read_index_response(&mut self, from: u64, response: ReadIndexResponse) {
+        let Some(read) = self.forwarded_reads.remove(&response.request_id) else {
+            return;
+        };
+        if read.term != response.term || read.leader_id != from {
+            log::debug!(
+                "Server {} ignored a read index answer from node {} in term {}",
+                self.state.server_id,
+                from,
+                response.term
+            );
+            self.forwarded_reads.insert(response.request_id, read);
+            return;
+        }
+        let ForwardedRead { query, response_channel, .. } = read;
+        if !response.success {
+            let _ = response_channel.send(Err(ReadError::NotReady));
+            return;
+        }
+        self.pending_reads.push_back(PendingRead {
+            id: 0,
+            read_index: response.read_index,
+            confirmed: true,
+            requester: ReadRequester::Client { query, response_channel },
+        });
+        self.serve_reads();
+    }
+
+    fn reply_read_index(&mut self, requester: ReadRequester, read_index: Option<u64>) {
+        let ReadRequester::Follower { node_id, request_id } = requester else {
+            return;
+        };
+        let response = ReadIndexResponse {
+            term: self.state.current_term,
+            request_id,
+            success: read_index.is_some(),
+            read_index: read_index.unwrap_or(0),
+        };
+        self.send(node_id, RpcMessage::ReadIndexResponse(response));
+    }
+
+    /// Whether reads can be answered without a heartbeat round
+    ///
+    /// The lease is given up as soon as a leadership transfer starts, since the
+    /// target's vote requests bypass the followers' CheckQuorum.
+    fn holds_lease(&self, now: Instant) -> bool {
+        matches!(self.config.read_mode, ReadMode::Lease { .. })
+            && self.leadership_transfer.is_none()
+            && self.lease_expiry.is_some_and(|expiry| now < expiry)
+    }
+
+    /// Records that `peer` acknowledged our leadership after heartbeat round
+    /// `read_id`, confirming every read of that round and earlier ones once a
+    /// majority did, and extending the lease from the time that round was sent
+    pub(crate) fn acknowledge_read(&mut self, peer: u64, read_id: u64) {
+        let Some(progress) = self.progress.get_mut(&peer).filter(|progress| read_id > progress.read_ack) else {
+            return;
+        };
+        progress.read_ack = read_id;
+
+        // The leader acknowledges every round it sends
+        let server_id = self.state.server_id;
+        let confirmed_id = self.configuration.quorum_index(|id| {
+            if id == server_id {
+                u64::MAX
+            } else {
+                self.progress.get(&id).map_or(0, |progress| progress.read_ack)
+            }
+        });
+        for read in self.pending_reads.iter_mut().filter(|read| read.id <= confirmed_id) {
+            read.confirmed = true;
+        }
+        self.extend_lease(confirmed_id);
+        self.serve_reads();
+    }
+
+    /// Extends the lease from the time heartbeat round `confirmed_id` was sent,
+    /// since a majority acknowledged it
+    fn extend_lease(&mut self, confirmed_id: u64) {
+        let ReadMode::Lease { max_clock_drift } = self.config.read_mode else {
+            return;
+        };
+        while self.round_starts.front().is_some_and(|(id, _)| *id < confirmed_id) {
+            self.round_starts.pop_front();
+        }
+        if let Some((id, sent_at)) = self.round_starts.front()
+            && *id == confirmed_id
+            && self.leadership_transfer.is_none()
+        {
+            let expiry = *sent_at + self.config.election_timeout_min.saturating_sub(max_clock_drift);
+            self.lease_expiry = self.lease_expiry.max(Some(expiry));
+        }
+    }
+
+    /// Answers the confirmed reads whose read index is applied
+    pub(crate) fn serve_reads(&mut self) {
+        while self
+            .pending_reads
+            .front()
+            .is_some_and(|read| read.confirmed && read.read_index <= self.state.last_applied.index)
+        {
+            let read = self.pending_reads.pop_front().unwrap();
+            match read.requester {
+                ReadRequester::Client { query, response_channel } => {
+                    let result = self.state_machine.query(&query);
+                    // The client may have given up waiting
+                    let _ = response_channel.send(Ok(result));
+                }
+                requester => self.reply_read_index(requester, Some(read.read_index)),
+            }
+        }
+    }
+
+    /// Fails the reads still waiting for the leadership to be confirmed once
+    /// this server is no longer the leader
+    ///
+    /// Confirmed reads stay valid: they are answered once their read index is
+    /// applied, whoever leads the cluster by then.
+    pub(crate) fn fail_pending_reads(&mut self) {
+        let (confirmed, unconfirmed) = self.pending_reads.drain(..).partition(|read| read.confirmed);
+        self.pending_reads = confirmed;
+        for read in unconfirmed {
+            match read.requester {
+                ReadRequester::Client { response_channel, .. } => {
+                    let _ = response_channel.send(Err(ReadError::NotLeader { leader_id: self.leader_id }));
+                }
+                requester => self.reply_read_index(requester, None),
+            }
+        }
+    }
+
+    /// Fails the reads forwarded to a leader this server no longer follows,
+    /// and drops those whose client gave up waiting
+    ///
+    /// Called whenever the term or the leader changes: the former leader may
+    /// never answer, and its answer would be dropped anyway.
+    pub(crate) fn fail_forwarded_reads(&mut self) {
+        let (current_term, leader_id) = (self.state.current_term, self.leader_id);
+        let (kept, failed): (HashMap<_, _>, HashMap<_, _>) = self
+            .forwarded_reads
+            .drain()
+            .filter(|(_, read)| !read.response_channel.is_closed())
+            .partition(|(_, read)| read.term == current_term && Some(read.leader_id) == leader_id);
+        self.forwarded_reads = kept;
+        for read in failed.into_values() {
+            let _ = read.response_channel.send(Err(ReadError::NotLeader { leader_id }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    use super::*;
+    use crate::log::LogEntry;
+    use crate::node::node_core::tests::{elected_leader, new_core};
+    use crate::rpc::{AppendEntryRequest, AppendEntryResponse};
+
+    fn leader_with_committed_entry() -> NodeCore {
+        let mut core = elected_leader(vec![2, 3]);
+        core.append_command(vec![7]);
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 2, read_id: 0, ..Default::default() }));
+        core.apply_committed();
+        core.take_outbox();
+        core
+    }
+
+    fn ack(core: &mut NodeCore, from: u64, read_id: u64) {
+        core.step(from, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 2, read_id, ..Default::default() }));
+    }
+
+    #[test]
+    fn read_is_answered_once_a_majority_confirms_the_leadership() {
+        let mut core = leader_with_committed_entry();
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+        let round = core.read_sequence;
+        assert!(core.take_outbox().iter().all(|(_, message)| matches!(message, RpcMessage::AppendEntry(request) if request.read_id == round)));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        ack(&mut core, 3, round);
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+    }
+
+    #[test]
+    fn acknowledgement_of_an_earlier_round_does_not_confirm_a_read() {
+        let mut core = leader_with_committed_entry();
+        let (first, _) = oneshot::channel();
+        let (second, mut receiver) = oneshot::channel();
+        core.read(vec![], first);
+        core.read(vec![], second);
+
+        let round = core.read_sequence;
+        ack(&mut core, 2, round - 1);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        ack(&mut core, 2, round);
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+    }
+
+    #[test]
+    fn read_is_not_ready_before_an_entry_of_the_term_commits() {
+        let mut core = elected_leader(vec![2, 3]);
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+
+        assert_eq!(receiver.try_recv(), Ok(Err(ReadError::NotReady)));
+    }
+
+    #[test]
+    fn idle_new_leader_serves_reads_once_its_noop_commits() {
+        let mut core = elected_leader(vec![2, 3]);
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 1, read_id: 0, ..Default::default() }));
+        core.apply_committed();
+        core.take_outbox();
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+        let round = core.read_sequence;
+        core.step(3, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 1, read_id: round, ..Default::default() }));
+
+        assert!(matches!(receiver.try_recv(), Ok(Ok(_))));
+    }
+
+    #[test]
+    fn pending_reads_fail_when_the_leader_steps_down() {
+        let mut core = leader_with_committed_entry();
+        let (sender, mut receiver) = oneshot::channel();
+        core.read(vec![], sender);
+
+        core.step(2, RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: 2,
+            leader_id: 2,
+            prev_log_index: 2,
+            prev_log_term: 1,
+            append_index: 3,
+            entries: vec![],
+            leader_commit: 2,
+            read_id: 0,
+        }));
+
+        assert_eq!(receiver.try_recv(), Ok(Err(ReadError::NotLeader { leader_id: Some(2) })));
+    }
+
+    fn leased_leader() -> NodeCore {
+        let mut core = leader_with_committed_entry();
+        core.config.read_mode = ReadMode::Lease { max_clock_drift: Duration::from_millis(10) };
+        core.broadcast_append();
+        core.take_outbox();
+        let round = core.read_sequence;
+        ack(&mut core, 2, round);
+        core
+    }
+
+    #[test]
+    fn lease_read_needs_no_heartbeat_round() {
+        let mut core = leased_leader();
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+        assert!(core.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn expired_lease_falls_back_to_read_index() {
+        let mut core = leased_leader();
+        core.lease_expiry = Some(Instant::now());
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert!(!core.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn lease_is_revoked_by_a_leadership_transfer() {
+        let mut core = leased_leader();
+        core.transfer_leadership(2).unwrap();
+        core.take_outbox();
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+        let round = core.read_sequence;
+        ack(&mut core, 3, round);
+
+        assert_eq!(core.lease_expiry, None);
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+    }
+
+    #[test]
+    fn leader_answers_a_follower_with_the_confirmed_read_index() {
+        let mut core = leader_with_committed_entry();
+
+        core.step(3, RpcMessage::ReadIndex(ReadIndexRequest { current_term: 1, request_id: 9 }));
+        core.take_outbox();
+        let round = core.read_sequence;
+        ack(&mut core, 2, round);
+
+        match core.take_outbox().pop() {
+            Some((3, RpcMessage::ReadIndexResponse(response))) => {
+                assert!(response.success);
+                assert_eq!((response.request_id, response.read_index), (9, 2));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    fn append_from_leader(entries: Vec<Vec<u8>>, prev_log_index: u64, leader_commit: u64) -> RpcMessage {
+        RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: 1,
+            leader_id: 1,
+            prev_log_index,
+            prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+            append_index: prev_log_index + 1,
+            entries: (prev_log_index + 1..).zip(entries).map(|(index, command)| LogEntry::command(1, index, command)).collect(),
+            leader_commit,
+            read_id: 0,
+        })
+    }
+
+    #[test]
+    fn follower_answers_a_read_once_it_applied_the_read_index() {
+        let mut core = new_core(2, vec![1, 3]);
+        core.step(1, append_from_leader(vec![vec![7]], 0, 0));
+        core.take_outbox();
+        let (sender, mut receiver) = oneshot::channel();
+
+        core.read(vec![], sender);
+        let request_id = match core.take_outbox().pop() {
+            Some((1, RpcMessage::ReadIndex(request))) => request.request_id,
+            other => panic!("unexpected message {:?}", other),
+        };
+        core.step(1, RpcMessage::ReadIndexResponse(ReadIndexResponse { term: 1, request_id, success: true, read_index: 1 }));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        core.step(1, append_from_leader(vec![], 1, 1));
+        core.apply_committed();
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+    }
+
+    /// Forwards a read from a follower of node 1 in term 1, returning its request ID
+    fn forwarded_read(core: &mut NodeCore) -> (u64, oneshot::Receiver<Result<Vec<u8>, ReadError>>) {
+        let (sender, receiver) = oneshot::channel();
+        core.read(vec![], sender);
+        match core.take_outbox().pop() {
+            Some((1, RpcMessage::ReadIndex(request))) => (request.request_id, receiver),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn follower_ignores_a_read_index_answer_from_another_term_or_node() {
+        let mut core = new_core(2, vec![1, 3]);
+        core.step(1, append_from_leader(vec![vec![7]], 0, 1));
+        core.apply_committed();
+        core.take_outbox();
+        let (request_id, mut receiver) = forwarded_read(&mut core);
+
+        core.step(1, RpcMessage::ReadIndexResponse(ReadIndexResponse { term: 0, request_id, success: true, read_index: 1 }));
+        core.step(3, RpcMessage::ReadIndexResponse(ReadIndexResponse { term: 1, request_id, success: true, read_index: 1 }));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        core.step(1, RpcMessage::ReadIndexResponse(ReadIndexResponse { term: 1, request_id, success: true, read_index: 1 }));
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![7])));
+    }
+
+    #[test]
+    fn forwarded_reads_fail_once_the_leader_changes() {
+        let mut core = new_core(2, vec![1, 3]);
+        core.step(1, append_from_leader(vec![vec![7]], 0, 1));
+        core.take_outbox();
+        let (_, mut receiver) = forwarded_read(&mut core);
+        let (_, abandoned) = forwarded_read(&mut core);
+        drop(abandoned);
+
+        core.become_follower(2, Some(3));
+
+        assert_eq!(receiver.try_recv(), Ok(Err(ReadError::NotLeader { leader_id: Some(3) })));
+        assert!(core.forwarded_reads.is_empty());
+    }
+}
@@ -0,0 +1,682 @@
+//! Log replication: the leader side sending AppendEntry and advancing the
+//! commit position, and the follower side applying AppendEntry to its log.
+
+use tokio::time::Instant;
+
+use super::membership::decode_configuration;
+use super::node_core::NodeCore;
+use super::progress::ProgressState;
+use super::read::ReadMode;
+use crate::log::{EntryKind, LogEntry, LogPosition};
+use crate::network::RpcMessage;
+use crate::rpc::{AppendEntryRequest, AppendEntryResponse};
+use crate::state::State;
+
+impl NodeCore {
+    /// Appends an entry to the leader's log and starts replicating it
+    ///
+    /// # Returns
+    /// * `Some(LogPosition)` - Position of the new entry
+    /// * `None` - This server is not the leader, or is handing its leadership over
+    pub(crate) fn append_entry(&mut self, kind: EntryKind, payload: Vec<u8>) -> Option<LogPosition> {
+        self.append_batch(kind, vec![payload])?.pop()
+    }
+
+    /// Appends a command to the leader's log, see `append_entry`
+    #[cfg(test)]
+    pub(crate) fn append_command(&mut self, command: Vec<u8>) -> Option<LogPosition> {
+        self.append_entry(EntryKind::Command, command)
+    }
+
+    /// Appends entries to the leader's log with a single storage write and
+    /// starts replicating them, at most one AppendEntry per peer
+    ///
+    /// # Returns
+    /// * `Some(positions)` - Positions of the new entries, in order
+    /// * `None` - This server is not the leader, or is handing its leadership over
+    pub(crate) fn append_batch(&mut self, kind: EntryKind, payloads: Vec<Vec<u8>>) -> Option<Vec<LogPosition>> {
+        if self.state.state != State::Leader || self.leadership_transfer.is_some() {
+            return None;
+        }
+
+        let (term, first_index) = (self.state.current_term, self.state.last_log_index() + 1);
+        let entries: Vec<_> = (first_index..)
+            .zip(payloads)
+            .map(|(index, payload)| LogEntry::new(term, index, kind, payload))
+            .collect();
+        let positions = entries.iter().map(|entry| LogPosition::new(entry.term, entry.index)).collect();
+        self.append_to_log(entries);
+
+        for peer in self.peers.clone() {
+            if self.progress.get(&peer).is_some_and(|progress| !progress.is_paused(&self.config.flow_control)) {
+                self.send_append(peer);
+            }
+        }
+        // A single-node cluster commits as soon as the entries are in its own log
+        self.advance_commit();
+        Some(positions)
+    }
+
+    /// Sends an AppendEntry to every peer
+    ///
+    /// Doubles as the leader's heartbeat and as the retransmission of entries
+    /// whose AppendEntry or response was lost.
+    ///
+    /// Every broadcast starts a new heartbeat round, whose acknowledgement by a
+    /// majority confirms this server still leads the cluster (see `acknowledge_read`).
+    pub(crate) fn broadcast_append(&mut self) {
+        let now = Instant::now();
+        self.heartbeat_deadline = now + self.config.heartbeat_interval;
+        self.read_sequence += 1;
+        if matches!(self.config.read_mode, ReadMode::Lease { .. }) {
+            self.round_starts.push_back((self.read_sequence, now));
+        }
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    /// Sends the entries from the peer's `next_index` on, or a heartbeat if
+    /// the peer is up to date
+    ///
+    /// A peer that needs compacted entries is sent the snapshot instead. A
+    /// peer in Replicate whose in-flight window is full is only sent a
+    /// heartbeat; one in Probe is sent its unanswered entries again.
+    pub(crate) fn send_append(&mut self, peer: u64) {
+        let Some(progress) = self.progress.get_mut(&peer) else {
+            return;
+        };
+        let next_index = progress.next_index;
+        // Entries the peer needs were compacted: only a snapshot can bring it up to date
+        if next_index <= self.state.snapshot_position.index || matches!(progress.state, ProgressState::Snapshot(_)) {
+            self.send_snapshot_chunk(peer);
+            return;
+        }
+        let prev_log_index = next_index - 1;
+        let prev_log_term = self.state.term_at(prev_log_index).unwrap_or(0);
+        let entries = if matches!(progress.state, ProgressState::Replicate) && progress.is_paused(&self.config.flow_control) {
+            Vec::new()
+        } else {
+            self.state.entries_from(next_index, self.config.max_entries_per_append, self.config.max_bytes_per_append)
+        };
+
+        if let Some(last) = entries.last() {
+            progress.sent(last.index, entries.iter().map(|entry| entry.len() as u64).sum());
+        }
+        let request = AppendEntryRequest {
+            current_term: self.state.current_term,
+            leader_id: self.state.server_id,
+            prev_log_index,
+            prev_log_term,
+            append_index: next_index,
+            entries,
+            leader_commit: self.state.commit_position.index,
+            read_id: self.read_sequence,
+        };
+        self.send(peer, RpcMessage::AppendEntry(request));
+    }
+
+    /// Handles an AppendEntry from the leader (Raft paper, figure 2)
+    ///
+    /// The request is rejected unless our log contains an entry at `prev_log_index`
+    /// whose term matches `prev_log_term`. Otherwise every entry conflicting with
+    /// the new ones (same index, different term) is removed together with all
+    /// entries following it, missing entries are appended, and the commit position
+    /// follows the leader's up to the last entry known to match its log.
+    pub(crate) fn handle_append_entry(&mut self, from: u64, mut request: AppendEntryRequest) {
+        if request.current_term < self.state.current_term {
+            self.reject_append(from, request.prev_log_index, request.read_id);
+            return;
+        }
+        // A configuration entry that does not decode must never reach our log
+        if request
+            .entries
+            .iter()
+            .any(|entry| entry.kind == EntryKind::Configuration && decode_configuration(entry).is_none())
+        {
+            log::warn!(
+                "Server {} rejects an AppendEntry from {} carrying a malformed configuration entry",
+                self.state.server_id,
+                from
+            );
+            self.reject_append(from, request.prev_log_index, request.read_id);
+            return;
+        }
+
+        // A candidate that hears from the leader of its own term steps back
+        if self.state.state != State::Follower {
+            self.become_follower(request.current_term, Some(request.leader_id));
+        }
+        self.leader_id = Some(request.leader_id);
+        self.last_leader_contact = Some(Instant::now());
+        self.reset_election_deadline();
+
+        // Entries covered by our snapshot are committed, so they match the leader's
+        let snapshot_position = &self.state.snapshot_position;
+        if request.prev_log_index < snapshot_position.index {
+            let covered = snapshot_position.index - request.prev_log_index;
+            request.entries.drain(..covered.min(request.entries.len() as u64) as usize);
+            request.prev_log_index = snapshot_position.index;
+            request.prev_log_term = snapshot_position.term;
+        }
+
+        if self.state.term_at(request.prev_log_index) != Some(request.prev_log_term) {
+            self.reject_append(from, request.prev_log_index, request.read_id);
+            return;
+        }
+
+        let read_id = request.read_id;
+        let index = request.prev_log_index + request.entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in request.entries {
+            if new_entries.is_empty() {
+                match self.state.term_at(entry.index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => self.truncate_log(entry.index),
+                    None => {}
+                }
+            }
+            new_entries.push(entry);
+        }
+        // The reply below is held until the new entries are durable (see `reply_append`)
+        self.append_to_log(new_entries);
+
+        if request.leader_commit > self.state.commit_position.index {
+            let commit_index = request.leader_commit.min(index);
+            if commit_index > self.state.commit_position.index {
+                self.state.commit_position = self.position_at(commit_index);
+            }
+        }
+        self.reply_append(from, index, read_id);
+    }
+
+    /// Handles a follower's answer to an AppendEntry
+    ///
+    /// On success the follower's match/next indexes move forward, it switches to
+    /// Replicate, the commit position may advance and the entries not sent yet
+    /// follow if the in-flight window allows; on failure `next_index` is walked
+    /// back past the conflicting entries (see `resume_index`), the follower
+    /// switches to Probe and the AppendEntry is retried, unless the rejection
+    /// is stale (see `Progress::reject`).
+    pub(crate) fn handle_append_entry_response(&mut self, from: u64, response: AppendEntryResponse) {
+        if self.state.state != State::Leader || response.term != self.state.current_term {
+            return;
+        }
+        let Some(progress) = self.progress.get_mut(&from) else {
+            return;
+        };
+
+        // A rejection also proves the follower is reachable and follows us
+        progress.last_contact = Some(Instant::now());
+        if matches!(progress.state, ProgressState::Snapshot(_)) {
+            // A late answer must not interrupt the snapshot transfer that replaced AppendEntry
+        } else if response.success {
+            progress.acknowledge(response.match_index);
+            let send_more = progress.next_index <= self.state.last_log_index() && !progress.is_paused(&self.config.flow_control);
+            self.advance_commit();
+
+            if send_more {
+                self.send_append(from);
+            }
+            self.continue_leadership_transfer(from);
+        } else {
+            let next_index = self.resume_index(&response);
+            if self.progress.get_mut(&from).unwrap().reject(response.rejected_index, next_index) {
+                self.send_append(from);
+            }
+        }
+        self.acknowledge_read(from, response.read_id);
+    }
+
+    /// Sends the AppendEntry reply held until its entries became durable, and
+    /// lets a leader count its newly durable entries
+    pub(crate) fn on_log_durable(&mut self) {
+        let durable_index = self.storage.durable_index();
+        if self
+            .held_append_response
+            .as_ref()
+            .is_some_and(|(_, response)| response.match_index <= durable_index)
+        {
+            let (to, response) = self.held_append_response.take().unwrap();
+            // A reply from an earlier term would be ignored by the leader anyway
+            if response.term == self.state.current_term {
+                self.send(to, RpcMessage::AppendEntryResponse(response));
+            }
+        }
+        if self.state.state == State::Leader {
+            self.advance_commit();
+        }
+    }
+
+    /// Moves the commit position to the highest index stored on a majority
+    ///
+    /// Only entries of the current term are committed by counting replicas;
+    /// earlier entries become committed indirectly (Raft paper, section 5.4.2).
+    pub(crate) fn advance_commit(&mut self) {
+        let server_id = self.state.server_id;
+        let durable_index = self.storage.durable_index();
+        let quorum_index = self.configuration.quorum_index(|id| {
+            // The leader only counts the entries of its own log that survive a crash
+            if id == server_id {
+                durable_index
+            } else {
+                self.progress.get(&id).map_or(0, |progress| progress.match_index)
+            }
+        });
+        if quorum_index > self.state.commit_position.index
+            && self.state.term_at(quorum_index) == Some(self.state.current_term)
+        {
+            self.state.commit_position = self.position_at(quorum_index);
+            log::debug!(
+                "Server {} committed up to index {}",
+                self.state.server_id,
+                quorum_index
+            );
+        }
+    }
+
+    /// Returns where to resume replication after a rejection, from the
+    /// follower's conflict hints
+    ///
+    /// If our log holds the conflicting term, the follower's entries of that
+    /// term up to our last one may match; otherwise none of them does and we
+    /// resume at the first one. A follower whose log is too short resumes
+    /// right after its last entry.
+    fn resume_index(&self, response: &AppendEntryResponse) -> u64 {
+        if response.conflict_term == 0 {
+            return response.last_log_index + 1;
+        }
+        match self.state.last_index_of_term(response.conflict_term) {
+            Some(last_index) => last_index + 1,
+            None => response.conflict_index,
+        }
+    }
+
+    /// Builds the position of `index`, using the current term for indexes past
+    /// the end of the log
+    fn position_at(&self, index: u64) -> LogPosition {
+        let term = self.state.term_at(index).unwrap_or(self.state.current_term);
+        LogPosition::new(term, index)
+    }
+
+    /// Accepts an AppendEntry
+    ///
+    /// A success acknowledging entries that are not durable yet is held until
+    /// they are (see `on_log_durable`): the leader counts this server as storing
+    /// them, which a crash must not make untrue. Only the held reply with the
+    /// highest `match_index` is kept, as it covers the others, and it echoes the
+    /// latest `read_id`.
+    fn reply_append(&mut self, to: u64, match_index: u64, read_id: u64) {
+        let response = AppendEntryResponse {
+            term: self.state.current_term,
+            success: true,
+            match_index,
+            read_id,
+            last_log_index: self.state.last_log_index(),
+            ..AppendEntryResponse::default()
+        };
+        if self
+            .held_append_response
+            .as_ref()
+            .is_some_and(|(_, held)| held.match_index <= match_index)
+        {
+            self.held_append_response = None;
+        }
+        if match_index > self.storage.durable_index() {
+            match self.held_append_response.as_mut() {
+                Some((_, held)) => held.read_id = held.read_id.max(read_id),
+                None => self.held_append_response = Some((to, response)),
+            }
+            return;
+        }
+        self.send(to, RpcMessage::AppendEntryResponse(response));
+    }
+
+    /// Rejects an AppendEntry, hinting where the leader should resume
+    ///
+    /// If our log holds an entry at `prev_log_index`, its term conflicts with
+    /// the leader's: the hint is the first entry of that term, so the leader
+    /// skips the whole term at once. Otherwise our log is too short and the
+    /// hint is its end.
+    fn reject_append(&mut self, to: u64, prev_log_index: u64, read_id: u64) {
+        let last_log_index = self.state.last_log_index();
+        let conflict = self.state.term_at(prev_log_index).zip(self.state.first_index_of_term(prev_log_index));
+        let (conflict_term, conflict_index) = conflict.unwrap_or((0, last_log_index + 1));
+        let response = AppendEntryResponse {
+            term: self.state.current_term,
+            success: false,
+            match_index: 0,
+            read_id,
+            conflict_term,
+            conflict_index,
+            last_log_index,
+            rejected_index: prev_log_index,
+        };
+        self.send(to, RpcMessage::AppendEntryResponse(response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::node::node_core::tests::{append_commands, new_core};
+    use crate::node::progress::FlowControl;
+    use crate::state_machine::tests::EchoStateMachine;
+    use crate::storage::file_storage::FileStorage;
+    use crate::storage::wal::{DurabilityPolicy, WalOptions};
+
+    fn append_request(term: u64, prev: (u64, u64), entries_term: u64, entries: Vec<Vec<u8>>, leader_commit: u64) -> RpcMessage {
+        RpcMessage::AppendEntry(AppendEntryRequest {
+            current_term: term,
+            leader_id: 2,
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            append_index: prev.0 + 1,
+            entries: (prev.0 + 1..).zip(entries).map(|(index, command)| LogEntry::command(entries_term, index, command)).collect(),
+            leader_commit,
+            read_id: 0,
+        })
+    }
+
+    /// A core whose entries only become durable on an explicit sync
+    fn group_commit_core(directory: &Path, server_id: u64, peers: Vec<u64>) -> NodeCore {
+        let options = WalOptions {
+            durability: DurabilityPolicy::GroupCommit {
+                max_entries: 100,
+                max_delay: Duration::from_secs(60),
+            },
+            ..WalOptions::default()
+        };
+        let storage = FileStorage::open_with_options(directory, options).unwrap();
+        NodeCore::new(server_id, peers, Box::new(EchoStateMachine::default()), Box::new(storage)).unwrap()
+    }
+
+    fn append_response(core: &mut NodeCore) -> AppendEntryResponse {
+        match core.take_outbox().pop() {
+            Some((_, RpcMessage::AppendEntryResponse(response))) => response,
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn follower_rejects_mismatching_prev_entry() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1]]);
+
+        core.step(2, append_request(2, (1, 2), 2, vec![vec![2]], 0));
+
+        assert!(!append_response(&mut core).success);
+        assert_eq!(core.state.last_log_index(), 1);
+    }
+
+    #[test]
+    fn follower_rejects_a_malformed_configuration_entry() {
+        let mut core = new_core(1, vec![2, 3]);
+        let mut request = match append_request(1, (0, 0), 1, vec![vec![1]], 0) {
+            RpcMessage::AppendEntry(request) => request,
+            other => panic!("unexpected message {:?}", other),
+        };
+        request.entries.push(LogEntry::new(1, 2, EntryKind::Configuration, b"not a configuration".to_vec()));
+
+        core.step(2, RpcMessage::AppendEntry(request));
+
+        assert!(!append_response(&mut core).success);
+        assert_eq!(core.state.last_log_index(), 0);
+    }
+
+    #[test]
+    fn follower_replaces_conflicting_suffix() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1], vec![2]]);
+        append_commands(&mut core, 2, vec![vec![3], vec![4]]);
+
+        core.step(2, append_request(3, (2, 1), 3, vec![vec![5]], 3));
+
+        let response = append_response(&mut core);
+        assert!(response.success);
+        assert_eq!(response.match_index, 3);
+        assert_eq!(core.state.last_log_index(), 3);
+        assert_eq!(core.state.term_at(3), Some(3));
+        assert_eq!(core.state.entry_at(3), Some(&LogEntry::command(3, 3, vec![5])));
+        assert_eq!(core.state.commit_position, LogPosition::new(3, 3));
+        assert_eq!(core.storage.last_index(), 3);
+        assert_eq!(core.storage.entries(3, 4).unwrap()[0].term, 3);
+    }
+
+    #[test]
+    fn follower_rejection_hints_at_the_start_of_the_conflicting_term() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1], vec![2]]);
+        append_commands(&mut core, 2, vec![vec![3], vec![4], vec![5]]);
+
+        core.step(2, append_request(3, (5, 3), 3, vec![vec![6]], 0));
+        let response = append_response(&mut core);
+        assert!(!response.success);
+        assert_eq!((response.conflict_term, response.conflict_index, response.last_log_index), (2, 3, 5));
+
+        // A log too short to hold prev_log_index hints at its end
+        core.step(2, append_request(3, (9, 3), 3, vec![vec![10]], 0));
+        let response = append_response(&mut core);
+        assert_eq!((response.conflict_term, response.conflict_index, response.last_log_index), (0, 6, 5));
+    }
+
+    #[test]
+    fn leader_skips_a_conflicting_term_in_a_single_round_trip() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1], vec![2]]);
+        append_commands(&mut core, 3, vec![vec![3]; 20]);
+        core.state.current_term = 4;
+        core.become_leader();
+        core.take_outbox();
+        let reject = |conflict_term, conflict_index, last_log_index| {
+            RpcMessage::AppendEntryResponse(AppendEntryResponse {
+                term: 4,
+                success: false,
+                conflict_term,
+                conflict_index,
+                last_log_index,
+                rejected_index: 22,
+                ..Default::default()
+            })
+        };
+        let prev_log_index = |core: &mut NodeCore| match core.take_outbox().pop() {
+            Some((2, RpcMessage::AppendEntry(request))) => request.prev_log_index,
+            other => panic!("unexpected message {:?}", other),
+        };
+
+        // The follower's term 2 entries from index 3 on are not in our log
+        core.step(2, reject(2, 3, 40));
+        assert_eq!(prev_log_index(&mut core), 2);
+
+        // Our entries of term 1 match the follower's up to our last one
+        core.progress.get_mut(&2).unwrap().next_index = 23;
+        core.step(2, reject(1, 1, 30));
+        assert_eq!(prev_log_index(&mut core), 2);
+
+        // A short log resumes after its last entry
+        core.progress.get_mut(&2).unwrap().next_index = 23;
+        core.step(2, reject(0, 8, 7));
+        assert_eq!(prev_log_index(&mut core), 7);
+    }
+
+    #[test]
+    fn follower_keeps_entries_after_a_stale_request() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.state.current_term = 1;
+        append_commands(&mut core, 1, vec![vec![1], vec![2], vec![3]]);
+
+        // A delayed copy of an older request must not cut the log short
+        core.step(2, append_request(1, (0, 0), 1, vec![vec![1]], 0));
+
+        assert_eq!(append_response(&mut core).match_index, 1);
+        assert_eq!(core.state.last_log_index(), 3);
+    }
+
+    #[test]
+    fn follower_skips_entries_covered_by_its_snapshot() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1], vec![2]]);
+        core.state.commit_position = LogPosition::new(1, 2);
+        core.apply_committed();
+        core.take_snapshot();
+
+        // A new leader may still think the follower needs entries it compacted
+        core.step(2, append_request(2, (0, 0), 1, vec![vec![1], vec![2], vec![3]], 2));
+
+        let response = append_response(&mut core);
+        assert!(response.success);
+        assert_eq!(response.match_index, 3);
+        assert_eq!(core.state.entry_at(3), Some(&LogEntry::command(1, 3, vec![3])));
+    }
+
+    #[test]
+    fn leader_commits_only_entries_of_its_term_by_counting() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1]]);
+        core.state.current_term = 2;
+        core.become_leader();
+        core.take_outbox();
+
+        // Replicating the old entry on a majority does not commit it...
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 2, success: true, match_index: 1, read_id: 0, ..Default::default() }));
+        assert_eq!(core.state.commit_position.index, 0);
+
+        // ...but committing the no-op of the current term commits it indirectly
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 2, success: true, match_index: 2, read_id: 0, ..Default::default() }));
+        assert_eq!(core.state.commit_position, LogPosition::new(2, 2));
+    }
+
+    #[test]
+    fn new_leader_sends_its_no_op_with_the_entries_of_earlier_terms() {
+        let mut core = new_core(1, vec![2, 3]);
+        append_commands(&mut core, 1, vec![vec![1], vec![2]]);
+        core.state.current_term = 2;
+        core.become_leader();
+        core.take_outbox();
+
+        core.progress.get_mut(&2).unwrap().next_index = 1;
+        core.send_append(2);
+
+        let entries = match core.take_outbox().pop() {
+            Some((2, RpcMessage::AppendEntry(request))) => request.entries,
+            other => panic!("unexpected message {:?}", other),
+        };
+        let positions: Vec<_> = entries.iter().map(|entry| (entry.term, entry.index, entry.kind)).collect();
+        assert_eq!(positions, vec![(1, 1, EntryKind::Command), (1, 2, EntryKind::Command), (2, 3, EntryKind::Noop)]);
+    }
+
+    #[test]
+    fn leader_pipelines_appends_and_probes_again_on_rejection() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.config.flow_control = FlowControl { max_in_flight_appends: 2, max_in_flight_bytes: 1024 };
+        core.state.current_term = 1;
+        core.become_leader();
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 1, ..Default::default() }));
+        core.take_outbox();
+        let sent_to_2 = |core: &mut NodeCore| -> Vec<(u64, usize)> {
+            core.take_outbox()
+                .into_iter()
+                .filter_map(|message| match message {
+                    (2, RpcMessage::AppendEntry(request)) => Some((request.prev_log_index, request.entries.len())),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Each new entry leaves at once until two appends await an answer
+        for command in 1..=3u8 {
+            core.append_command(vec![command]);
+        }
+        assert_eq!(sent_to_2(&mut core), vec![(1, 1), (2, 1)]);
+
+        // A full window only lets the heartbeat through
+        core.broadcast_append();
+        assert_eq!(sent_to_2(&mut core), vec![(3, 0)]);
+
+        // An answer to the first append sends the entry that waited
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 2, ..Default::default() }));
+        assert_eq!(sent_to_2(&mut core), vec![(3, 1)]);
+
+        // The second append was lost: the follower rejects the third and is probed
+        let rejection = AppendEntryResponse { term: 1, success: false, last_log_index: 2, rejected_index: 3, ..Default::default() };
+        core.step(2, RpcMessage::AppendEntryResponse(rejection.clone()));
+        assert_eq!(sent_to_2(&mut core), vec![(2, 2)]);
+        assert!(matches!(core.progress[&2].state, ProgressState::Probe));
+        // A duplicate of the rejection neither walks back nor sends again
+        core.step(2, RpcMessage::AppendEntryResponse(rejection));
+        assert!(sent_to_2(&mut core).is_empty());
+        assert_eq!(core.progress[&2].next_index, 3);
+        core.append_command(vec![4]);
+        assert!(sent_to_2(&mut core).is_empty());
+    }
+
+    #[test]
+    fn append_carries_at_most_the_configured_bytes_but_at_least_one_entry() {
+        let mut core = new_core(1, vec![2, 3]);
+        core.config.max_bytes_per_append = 10;
+        append_commands(&mut core, 1, vec![vec![0; 4], vec![0; 4], vec![0; 4], vec![0; 16]]);
+        core.state.current_term = 2;
+        core.become_leader();
+        core.take_outbox();
+        let entry_count = |core: &mut NodeCore, next_index: u64| {
+            core.progress.get_mut(&2).unwrap().next_index = next_index;
+            core.progress.get_mut(&2).unwrap().in_flight.clear();
+            core.send_append(2);
+            match core.take_outbox().pop() {
+                Some((2, RpcMessage::AppendEntry(request))) => request.entries.len(),
+                other => panic!("unexpected message {:?}", other),
+            }
+        };
+
+        assert_eq!(entry_count(&mut core, 1), 2);
+        assert_eq!(entry_count(&mut core, 4), 1);
+    }
+
+    #[test]
+    fn follower_holds_reply_until_entries_are_durable() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut core = group_commit_core(directory.path(), 1, vec![2, 3]);
+
+        core.step(2, append_request(1, (0, 0), 1, vec![vec![1], vec![2]], 0));
+        assert!(core.take_outbox().is_empty());
+
+        core.sync_storage();
+        assert_eq!(append_response(&mut core).match_index, 2);
+    }
+
+    #[test]
+    fn leader_replicates_its_entries_before_they_are_durable() {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut core = NodeCore::new(1, vec![2, 3], Box::new(EchoStateMachine::default()), Box::new(storage)).unwrap();
+        core.state.current_term = 1;
+        core.become_leader();
+        assert_eq!(core.take_outbox().len(), 2);
+        assert_eq!(core.storage.durable_index(), 0);
+
+        // A follower and a leader whose copy is not durable are no majority...
+        core.step(2, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 1, ..Default::default() }));
+        assert_eq!(core.state.commit_position.index, 0);
+
+        // ...until the leader's own write is fsynced
+        core.sync_storage();
+        assert_eq!(core.state.commit_position, LogPosition::new(1, 1));
+    }
+
+    #[test]
+    fn leader_counts_only_its_durable_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut core = group_commit_core(directory.path(), 1, vec![]);
+        core.state.current_term = 1;
+        core.become_leader();
+
+        core.append_command(vec![1]);
+        assert_eq!(core.state.commit_position.index, 0);
+
+        core.sync_storage();
+        assert_eq!(core.state.commit_position, LogPosition::new(1, 2));
+    }
+}
@@ -0,0 +1,494 @@
+//! Snapshots: replacing the applied prefix of the log with a snapshot of the
+//! state machine so that the log does not grow forever and a restarted server
+//! does not replay it from the first entry, and sending the snapshot to
+//! followers that need compacted entries (InstallSnapshot).
+
+use std::sync::Arc;
+
+use tokio::time::{Duration, Instant};
+
+use super::node_core::{NodeCore, storage_failure};
+use super::progress::ProgressState;
+use crate::log::LogPosition;
+use crate::network::RpcMessage;
+use crate::rpc::{InstallSnapshotRequest, InstallSnapshotResponse};
+use crate::state::State;
+use crate::storage::raft_storage::Snapshot;
+
+/// Maximum number of snapshot bytes carried by a single InstallSnapshot
+pub(crate) const SNAPSHOT_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Time the leader waits before sending a snapshot again to a follower that
+/// could not restore it
+///
+/// Sending the same snapshot again is unlikely to help, so the failure is
+/// logged and retried slowly rather than in a loop.
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// When a server snapshots its state machine and compacts its log
+///
+/// A snapshot is taken as soon as any threshold is reached; `None` disables a
+/// threshold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    pub max_applied_entries: Option<u64>,   // Entries applied since the latest snapshot
+    pub max_log_bytes: Option<u64>,         // Size of the log in stable storage
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            max_applied_entries: Some(10_000),
+            max_log_bytes: Some(64 * 1024 * 1024),
+        }
+    }
+}
+
+/// Progress of the leader sending its snapshot to one follower
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotTransfer {
+    snapshot: Arc<Snapshot>,   // Snapshot being sent, kept even if a newer one is taken meanwhile
+    offset: u64,               // Offset of the next chunk to send
+    paused_until: Option<Instant>, // Before when nothing is sent, after the follower rejected the snapshot
+}
+
+impl NodeCore {
+    /// Takes a snapshot if entries were applied since the latest one and the
+    /// snapshot policy asks for it
+    pub(crate) fn maybe_snapshot(&mut self) {
+        let applied_entries = self.state.last_applied.index - self.state.snapshot_position.index;
+        if applied_entries == 0 {
+            return;
+        }
+        let policy = &self.config.snapshot_policy;
+        let due = policy.max_applied_entries.is_some_and(|max| applied_entries >= max)
+            || policy.max_log_bytes.is_some_and(|max| self.storage.log_size() >= max);
+        if due {
+            self.take_snapshot();
+        }
+    }
+
+    /// Snapshots the state machine at `last_applied` and discards the log up to it
+    pub(crate) fn take_snapshot(&mut self) {
+        let snapshot = Snapshot {
+            last_included: self.state.last_applied.clone(),
+            configuration: self.applied_configuration.clone(),
+            data: self.state_machine.snapshot(),
+        };
+        // The snapshot must be durable before the entries it replaces are discarded
+        if let Err(error) = self.storage.save_snapshot(&snapshot) {
+            storage_failure(error);
+        }
+        if let Err(error) = self.storage.compact(snapshot.last_included.index) {
+            storage_failure(error);
+        }
+        log::info!(
+            "Server {} took a snapshot up to index {}",
+            self.state.server_id,
+            snapshot.last_included.index
+        );
+        self.state.compact_to(snapshot.last_included);
+    }
+
+    /// Sends the next chunk of the snapshot to a peer, starting a transfer of
+    /// the latest snapshot if none is in progress
+    ///
+    /// Only one chunk is outstanding at a time; a lost chunk is sent again with
+    /// the next heartbeat.
+    pub(crate) fn send_snapshot_chunk(&mut self, peer: u64) {
+        let Some(progress) = self.progress.get_mut(&peer) else {
+            return;
+        };
+        if !matches!(progress.state, ProgressState::Snapshot(_)) {
+            let snapshot = match self.storage.load_snapshot() {
+                Ok(Some(snapshot)) => snapshot,
+                Ok(None) => unreachable!("entries were compacted without a snapshot"),
+                Err(error) => storage_failure(error),
+            };
+            log::info!(
+                "Server {} sends its snapshot up to index {} to node {}",
+                self.state.server_id,
+                snapshot.last_included.index,
+                peer
+            );
+            progress.send_snapshot(SnapshotTransfer {
+                snapshot: Arc::new(snapshot),
+                offset: 0,
+                paused_until: None,
+            });
+        }
+        let ProgressState::Snapshot(transfer) = &mut progress.state else {
+            unreachable!("the snapshot transfer was just started");
+        };
+        if transfer.paused_until.is_some_and(|paused_until| Instant::now() < paused_until) {
+            return;
+        }
+        transfer.paused_until = None;
+
+        let start = transfer.offset as usize;
+        let end = (start + self.snapshot_chunk_size).min(transfer.snapshot.data.len());
+        let request = InstallSnapshotRequest {
+            current_term: self.state.current_term,
+            leader_id: self.state.server_id,
+            last_included_index: transfer.snapshot.last_included.index,
+            last_included_term: transfer.snapshot.last_included.term,
+            configuration: transfer.snapshot.configuration.clone(),
+            offset: transfer.offset,
+            data: transfer.snapshot.data[start..end].to_vec(),
+            done: end == transfer.snapshot.data.len(),
+        };
+        self.send(peer, RpcMessage::InstallSnapshot(request));
+    }
+
+    /// Handles a snapshot chunk from the leader
+    ///
+    /// Chunks are accumulated until the last one arrives, then the snapshot
+    /// replaces the state machine and the log it covers. Log entries following
+    /// the snapshot are kept if the log agrees with the snapshot's last entry,
+    /// otherwise the whole log is discarded (Raft paper, section 7).
+    pub(crate) fn handle_install_snapshot(&mut self, from: u64, request: InstallSnapshotRequest) {
+        if request.current_term < self.state.current_term {
+            self.reply_install_snapshot(from, request.last_included_index, 0, false);
+            return;
+        }
+
+        if self.state.state != State::Follower {
+            self.become_follower(request.current_term, Some(request.leader_id));
+        }
+        self.leader_id = Some(request.leader_id);
+        self.last_leader_contact = Some(Instant::now());
+        self.reset_election_deadline();
+
+        let last_included = LogPosition::new(request.last_included_term, request.last_included_index);
+        // Committed entries match the leader's log, so they already cover the snapshot
+        if last_included.index <= self.state.commit_position.index {
+            self.incoming_snapshot = None;
+            self.reply_install_snapshot(from, last_included.index, request.offset, true);
+            return;
+        }
+
+        if request.offset == 0 {
+            self.incoming_snapshot = Some(Snapshot {
+                last_included: last_included.clone(),
+                configuration: request.configuration,
+                data: Vec::new(),
+            });
+        }
+        let expected_offset = match &self.incoming_snapshot {
+            Some(incoming) if incoming.last_included == last_included => incoming.data.len() as u64,
+            _ => 0,
+        };
+        if request.offset != expected_offset {
+            self.reply_install_snapshot(from, last_included.index, expected_offset, false);
+            return;
+        }
+
+        let incoming = self.incoming_snapshot.as_mut().unwrap();
+        incoming.data.extend_from_slice(&request.data);
+        let next_offset = incoming.data.len() as u64;
+        if !request.done {
+            self.reply_install_snapshot(from, last_included.index, next_offset, false);
+            return;
+        }
+
+        let snapshot = self.incoming_snapshot.take().unwrap();
+        if self.install_snapshot(snapshot) {
+            self.reply_install_snapshot(from, last_included.index, next_offset, true);
+        } else {
+            let response = InstallSnapshotResponse {
+                term: self.state.current_term,
+                last_included_index: last_included.index,
+                next_offset: 0,
+                done: false,
+                rejected: true,
+            };
+            self.send(from, RpcMessage::InstallSnapshotResponse(response));
+        }
+    }
+
+    /// Replaces the state machine and the log up to the snapshot's last entry,
+    /// and adopts the snapshot's configuration unless the remaining log holds a
+    /// later one
+    ///
+    /// # Returns
+    /// * `false` if the state machine could not decode the snapshot
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> bool {
+        if let Err(error) = self.state_machine.restore(&snapshot.data) {
+            log::error!(
+                "Server {} could not restore the snapshot up to index {}: {}",
+                self.state.server_id,
+                snapshot.last_included.index,
+                error
+            );
+            return false;
+        }
+
+        let last_included = snapshot.last_included.clone();
+        // The conflicting entries are durably gone before the snapshot is saved:
+        // after a crash in between, the log reloaded past the snapshot would
+        // not follow its last entry
+        if self.state.term_at(last_included.index) != Some(last_included.term) {
+            self.truncate_log(self.state.commit_position.index + 1);
+        }
+        if let Err(error) = self.storage.save_snapshot(&snapshot) {
+            storage_failure(error);
+        }
+        if let Err(error) = self.storage.compact(last_included.index) {
+            storage_failure(error);
+        }
+        self.state.compact_to(last_included.clone());
+        self.state.commit_position = last_included.clone();
+        self.state.last_applied = last_included.clone();
+        self.applied_configuration = snapshot.configuration;
+        self.refresh_configuration();
+        // The outcome of these proposals is unknown; dropping them lets the
+        // proposers time out
+        self.pending_responses.retain(|index, _| *index > last_included.index);
+
+        log::info!(
+            "Server {} installed a snapshot up to index {}",
+            self.state.server_id,
+            last_included.index
+        );
+        true
+    }
+
+    /// Handles a follower's answer to an InstallSnapshot
+    ///
+    /// Once the snapshot is installed the follower is known to hold everything up
+    /// to its last entry and AppendEntry takes over; a snapshot the follower
+    /// could not restore is sent again from the start after
+    /// `SNAPSHOT_RETRY_DELAY`; otherwise the next chunk, or the chunk the
+    /// follower expects, is sent.
+    pub(crate) fn handle_install_snapshot_response(&mut self, from: u64, response: InstallSnapshotResponse) {
+        if self.state.state != State::Leader || response.term != self.state.current_term {
+            return;
+        }
+        let Some(progress) = self.progress.get_mut(&from) else {
+            return;
+        };
+        progress.last_contact = Some(Instant::now());
+        let ProgressState::Snapshot(transfer) = &mut progress.state else {
+            return;
+        };
+        if transfer.snapshot.last_included.index != response.last_included_index {
+            return;
+        }
+
+        if response.rejected {
+            log::error!(
+                "Node {} could not restore the snapshot up to index {}, sending it again in {:?}",
+                from,
+                response.last_included_index,
+                SNAPSHOT_RETRY_DELAY
+            );
+            transfer.offset = 0;
+            transfer.paused_until = Some(Instant::now() + SNAPSHOT_RETRY_DELAY);
+            return;
+        }
+        if !response.done {
+            transfer.offset = response.next_offset;
+            self.send_snapshot_chunk(from);
+            return;
+        }
+
+        progress.acknowledge(response.last_included_index);
+        self.advance_commit();
+        self.send_append(from);
+    }
+
+    fn reply_install_snapshot(&mut self, to: u64, last_included_index: u64, next_offset: u64, done: bool) {
+        let response = InstallSnapshotResponse {
+            term: self.state.current_term,
+            last_included_index,
+            next_offset,
+            done,
+            rejected: false,
+        };
+        self.send(to, RpcMessage::InstallSnapshotResponse(response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::LogEntry;
+    use crate::node::node_core::tests::{append_commands, new_core};
+    use crate::rpc::AppendEntryResponse;
+    use crate::state_machine::StateMachine;
+    use crate::state_machine::tests::EchoStateMachine;
+    use crate::configuration::Configuration;
+    use crate::rpc::InstallSnapshotRequest;
+    use crate::storage::file_storage::FileStorage;
+    use crate::storage::memory_storage::MemoryStorage;
+    use crate::storage::raft_storage::RaftStorage;
+
+    /// Delivers the messages `from` queued for `to`, dropping the others
+    fn deliver(from: &mut NodeCore, to: &mut NodeCore) -> usize {
+        let messages: Vec<_> = from
+            .take_outbox()
+            .into_iter()
+            .filter(|(target, _)| *target == to.state.server_id)
+            .collect();
+        let count = messages.len();
+        for (_, message) in messages {
+            to.step(from.state.server_id, message);
+        }
+        count
+    }
+
+    #[test]
+    fn snapshot_is_taken_once_enough_entries_are_applied() {
+        let mut core = new_core(1, vec![]);
+        core.config.snapshot_policy = SnapshotPolicy { max_applied_entries: Some(4), max_log_bytes: None };
+        core.state.current_term = 1;
+        core.become_leader();
+
+        core.append_command(vec![1]);
+        core.append_command(vec![2]);
+        core.apply_committed();
+        assert_eq!(core.state.snapshot_position.index, 0);
+
+        core.append_command(vec![3]);
+        core.append_command(vec![4]);
+        core.apply_committed();
+        assert_eq!(core.state.snapshot_position, LogPosition::new(1, 5));
+        assert_eq!(core.state.last_log_index(), 5);
+        assert_eq!(core.state.entry_at(5), None);
+        assert_eq!(core.storage.first_index(), 6);
+    }
+
+    #[test]
+    fn restarted_server_restores_the_snapshot() {
+        let mut core = new_core(1, vec![]);
+        core.state.current_term = 1;
+        core.become_leader();
+        core.append_command(vec![1]);
+        core.append_command(vec![2]);
+        core.apply_committed();
+        core.take_snapshot();
+        core.append_command(vec![3]);
+
+        let state_machine = EchoStateMachine::default();
+        let storage = std::mem::replace(&mut core.storage, Box::new(MemoryStorage::new()));
+        let restarted = NodeCore::new(1, vec![], Box::new(state_machine.clone()), storage).unwrap();
+
+        assert_eq!(restarted.state.last_applied, LogPosition::new(1, 3));
+        assert_eq!(restarted.state.last_log_index(), 4);
+        assert_eq!(restarted.state.entry_at(4), Some(&LogEntry::command(1, 4, vec![3])));
+        assert_eq!(state_machine.snapshot(), core.state_machine.snapshot());
+    }
+
+    #[test]
+    fn lagging_follower_catches_up_from_a_chunked_snapshot() {
+        let mut leader = new_core(1, vec![2, 3]);
+        leader.snapshot_chunk_size = 4;
+        leader.state.current_term = 1;
+        leader.become_leader();
+        // Server 2 is offline while server 3 acknowledges every entry, after the no-op
+        for command in 1..=5u8 {
+            leader.append_command(vec![command]);
+            leader.step(3, RpcMessage::AppendEntryResponse(AppendEntryResponse {
+                term: 1,
+                success: true,
+                match_index: command as u64 + 1,
+                ..Default::default()
+            }));
+        }
+        leader.apply_committed();
+        leader.take_snapshot();
+        leader.append_command(vec![6]);
+        leader.take_outbox();
+
+        let mut follower = new_core(2, vec![1, 3]);
+        leader.broadcast_append();
+        let mut rounds = 0;
+        while deliver(&mut leader, &mut follower) + deliver(&mut follower, &mut leader) > 0 {
+            rounds += 1;
+            assert!(rounds < 100, "snapshot transfer did not complete");
+        }
+
+        assert!(rounds > 2, "the snapshot was not sent in several chunks");
+        assert_eq!(follower.state.snapshot_position, LogPosition::new(1, 6));
+        assert_eq!(follower.state.last_applied, LogPosition::new(1, 6));
+        assert_eq!(follower.state_machine.snapshot(), leader.storage.load_snapshot().unwrap().unwrap().data);
+        assert_eq!(follower.state.entry_at(7), Some(&LogEntry::command(1, 7, vec![6])));
+        assert_eq!(leader.progress[&2].match_index, 7);
+    }
+
+    #[test]
+    fn conflicting_entries_are_gone_if_the_snapshot_save_fails() {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut core = NodeCore::new(2, vec![1, 3], Box::new(EchoStateMachine::default()), Box::new(storage)).unwrap();
+        append_commands(&mut core, 1, vec![vec![1], vec![2], vec![3]]);
+        // Saving the snapshot fails, as if the server crashed right before it
+        std::fs::create_dir(directory.path().join("snapshot")).unwrap();
+
+        let request = InstallSnapshotRequest {
+            current_term: 2,
+            leader_id: 1,
+            last_included_index: 5,
+            last_included_term: 2,
+            configuration: Configuration::new([1, 2, 3]),
+            offset: 0,
+            data: EchoStateMachine::default().snapshot(),
+            done: true,
+        };
+        let step = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            core.step(1, RpcMessage::InstallSnapshot(request));
+        }));
+        assert!(step.is_err());
+        drop(core);
+        std::fs::remove_dir(directory.path().join("snapshot")).unwrap();
+
+        let reopened = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(reopened.last_index(), 0);
+    }
+
+    #[test]
+    fn snapshot_the_follower_cannot_restore_is_sent_again_after_a_delay() {
+        let mut leader = new_core(1, vec![2, 3]);
+        leader.state.current_term = 1;
+        leader.become_leader();
+        leader.append_command(vec![1]);
+        leader.step(3, RpcMessage::AppendEntryResponse(AppendEntryResponse { term: 1, success: true, match_index: 2, ..Default::default() }));
+        leader.apply_committed();
+        leader.take_snapshot();
+        leader.append_command(vec![2]);
+        leader.take_outbox();
+        let chunks_to_2 = |leader: &mut NodeCore| {
+            leader.broadcast_append();
+            leader
+                .take_outbox()
+                .into_iter()
+                .filter_map(|message| match message {
+                    (2, RpcMessage::InstallSnapshot(mut request)) => {
+                        request.data = b"not a snapshot".to_vec();
+                        Some(request)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut follower = new_core(2, vec![1, 3]);
+        let request = chunks_to_2(&mut leader).pop().unwrap();
+        follower.step(1, RpcMessage::InstallSnapshot(request));
+        let response = match follower.take_outbox().pop() {
+            Some((1, RpcMessage::InstallSnapshotResponse(response))) => response,
+            other => panic!("unexpected message {:?}", other),
+        };
+        assert!(response.rejected && !response.done);
+        assert_eq!(follower.state.snapshot_position.index, 0);
+
+        leader.step(2, RpcMessage::InstallSnapshotResponse(response));
+        assert!(chunks_to_2(&mut leader).is_empty());
+
+        match &mut leader.progress.get_mut(&2).unwrap().state {
+            ProgressState::Snapshot(transfer) => transfer.paused_until = Some(Instant::now()),
+            state => panic!("unexpected state {:?}", state),
+        }
+        assert_eq!(chunks_to_2(&mut leader).pop().map(|request| request.offset), Some(0));
+    }
+}
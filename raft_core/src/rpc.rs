@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::Configuration;
 use crate::log::LogEntry;
 
 /// Request message sent by candidates during leader election
@@ -7,31 +10,86 @@ use crate::log::LogEntry;
 /// - It wins the election
 /// - Another server establishes itself as leader
 /// - A period of time goes by with no winner
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestVoteRequest {
     pub current_term: u64,      // Candidate's term number, used for term comparison
     pub candidate_id: u64,      // ID of the node requesting votes
     pub last_log_index: u64,    // Index of candidate's last log entry for log completeness check
     pub last_log_term: u64,     // Term of candidate's last log entry for log completeness check
+    #[serde(default)]
+    pub leadership_transfer: bool, // True if the leader asked the candidate to take over (TimeoutNow)
 }
 
 /// Response message for a RequestVote RPC
 /// 
 /// Followers respond to vote requests based on term numbers and log completeness.
 /// A candidate must receive votes from a majority of servers to become leader.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestVoteResponse {
     pub term: u64,              // Responding server's current term, for candidate to update itself
     pub vote_granted: bool,     // True means candidate received vote from this follower
 }
 
+/// Request message sent by the leader to hand its leadership over
+///
+/// The leader sends TimeoutNow once the target's log matches its own; the
+/// target then starts an election right away instead of waiting for its
+/// election timeout, and wins it unless another server has a newer log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutNowRequest {
+    pub current_term: u64,      // Leader's term; a stale request is ignored
+    pub leader_id: u64,         // ID of the leader handing over
+}
+
+/// Request message sent by a follower to learn the leader's read index
+///
+/// The leader answers once it confirmed it still leads the cluster; the
+/// follower then serves the read itself once it applied the read index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadIndexRequest {
+    pub current_term: u64,      // Follower's current term
+    pub request_id: u64,        // Identifies the read on the follower, echoed in the response
+}
+
+/// Response message for a ReadIndex RPC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadIndexResponse {
+    pub term: u64,              // Leader's current term
+    pub request_id: u64,        // request_id of the request answered
+    pub success: bool,          // False if the sender cannot serve reads (not the leader, or not ready)
+    pub read_index: u64,        // On success, the index the follower must apply before reading
+}
+
+/// Request message sent by a would-be candidate before starting an election
+///
+/// A server whose election timeout elapsed first asks the others whether they
+/// would vote for it, without incrementing its term. Only if a majority would
+/// does it become a candidate, so a server that cannot win (typically one that
+/// was partitioned away) does not force the cluster into a new term.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreVoteRequest {
+    pub next_term: u64,         // Term the sender would campaign in: its current term + 1
+    pub candidate_id: u64,      // ID of the node asking
+    pub last_log_index: u64,    // Index of the sender's last log entry for log completeness check
+    pub last_log_term: u64,     // Term of the sender's last log entry for log completeness check
+}
+
+/// Response message for a PreVote RPC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreVoteResponse {
+    pub term: u64,              // Responding server's current term, for the sender to update itself
+    pub vote_granted: bool,     // True if the responder would vote for the sender in next_term
+    #[serde(default)]
+    pub next_term: u64,         // next_term of the request answered
+}
+
 /// Request message sent by leader to replicate log entries and maintain heartbeat
 /// 
 /// Leaders send AppendEntries RPCs to all followers to:
 /// - Replicate new log entries
 /// - Maintain heartbeat signals
 /// - Update commit index across the cluster
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppendEntryRequest {
     pub current_term: u64,      // Leader's term, used by followers to detect stale leaders
     pub leader_id: u64,         // Leader's ID, so followers can redirect clients
@@ -40,17 +98,65 @@ pub struct AppendEntryRequest {
     pub prev_log_term: u64,     // Used for log consistency check
 
     pub append_index: u64,      // Starting index for appending new entries
-    pub entries: Vec<LogEntry>, // Log entries to store (empty for heartbeat)
+    pub entries: Vec<LogEntry>, // Log entries to store, each with its term and index (empty for heartbeat)
 
     pub leader_commit: u64,     // Leader's commit index to advance followers' commit index
+    #[serde(default)]
+    pub read_id: u64,           // Leader's latest ReadIndex round, echoed in the response
 }
 
 /// Response message for an AppendEntries RPC
 /// 
 /// Followers respond to append entries requests to indicate success or failure
-/// of log replication attempts. Failed attempts may trigger log backtracking.
-#[derive(Debug, Clone)]
+/// of log replication attempts. Failed attempts trigger log backtracking, which
+/// the conflict hints let the leader do a whole term at a time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AppendEntryResponse {
     pub term: u64,              // Follower's current term, for leader to update itself
     pub success: bool,          // True if follower contained entry matching prev_log_index/term
+    pub match_index: u64,       // On success, index of the last entry known to match the leader's log
+    #[serde(default)]
+    pub read_id: u64,           // read_id of the request answered, confirming the sender's leadership
+    #[serde(default)]
+    pub conflict_term: u64,     // On failure, term of the follower's entry at prev_log_index (0 if it has none)
+    #[serde(default)]
+    pub conflict_index: u64,    // On failure, first index the follower holds of conflict_term
+    #[serde(default)]
+    pub last_log_index: u64,    // Index of the follower's last entry
+    #[serde(default)]
+    pub rejected_index: u64,    // On failure, prev_log_index of the request rejected
+}
+
+/// Request message sent by the leader to transfer its snapshot to a follower
+///
+/// Used instead of AppendEntries when a follower needs entries the leader has
+/// already compacted. The snapshot is streamed in chunks, one request per chunk,
+/// the last one carrying `done`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshotRequest {
+    pub current_term: u64,          // Leader's term, used by followers to detect stale leaders
+    pub leader_id: u64,             // Leader's ID, so followers can redirect clients
+
+    pub last_included_index: u64,   // The snapshot replaces all entries up through this index
+    pub last_included_term: u64,    // Term of last_included_index
+
+    pub configuration: Configuration, // Cluster configuration as of last_included_index
+
+    pub offset: u64,                // Byte offset of the chunk in the snapshot data
+    pub data: Vec<u8>,              // Snapshot data, starting at offset
+    pub done: bool,                 // True if this is the last chunk
+}
+
+/// Response message for an InstallSnapshot RPC
+///
+/// Acknowledges a chunk, or tells the leader where to resume when a chunk did
+/// not follow the data received so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshotResponse {
+    pub term: u64,                  // Follower's current term, for leader to update itself
+    pub last_included_index: u64,   // Snapshot the response refers to
+    pub next_offset: u64,           // Offset of the next chunk the follower expects
+    pub done: bool,                 // True once the snapshot is installed
+    #[serde(default)]
+    pub rejected: bool,             // True if the follower could not restore the snapshot
 }
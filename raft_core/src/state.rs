@@ -1,16 +1,20 @@
-use crate::log::{LogPosition, SegmentLog};
+use crate::log::{LogEntry, LogPosition, SegmentLog};
 
 /// Represents the possible states/roles a Raft server can be in
 /// 
-/// In the Raft consensus algorithm, a server can be in one of three states:
+/// In the Raft consensus algorithm, a server can be in one of four states:
 /// - Leader: Handles all client requests and log replication
 /// - Follower: Passive state that responds to requests from leaders and candidates
+/// - PreCandidate: Used with PreVote when the election timeout elapses; polls the
+///   other servers without changing its term, and becomes a Candidate once a
+///   majority would vote for it
 /// - Candidate: Used during leader election when a follower becomes a candidate
 #[derive(Debug, Clone, PartialEq)]
 pub enum State {
-    Leader,     // Coordinates all system changes, handles client requests
-    Follower,   // Responds to RPCs from leaders and candidates
-    Candidate,  // Initiates leader elections, requests votes from other servers
+    Leader,         // Coordinates all system changes, handles client requests
+    Follower,       // Responds to RPCs from leaders and candidates
+    PreCandidate,   // Checks whether it could win an election before starting one
+    Candidate,      // Initiates leader elections, requests votes from other servers
 }
 
 /// Represents the complete state of a Raft server as described in the Raft paper.
@@ -24,15 +28,12 @@ pub struct RaftState {
     pub current_term: u64,        // Monotonically increasing term number
     pub voted_for: Option<u64>,   // Tracks which candidate received vote in current term
     pub logs: Vec<SegmentLog>,    // Log entries containing state machine commands and terms
+    pub snapshot_position: LogPosition,   // Last entry covered by the latest snapshot; `logs` starts right after it
 
     // Volatile state on all servers (rebuilt after crashes)
     pub commit_position: LogPosition,        // Highest log entry known to be committed (safe to apply)
     pub last_applied: LogPosition,           // Highest log entry applied to state machine
 
-    // Volatile state on leaders (reinitialized after election)
-    pub next_position: Vec<LogPosition>,     // Tracks next entry to send to each follower
-    pub match_position: Vec<LogPosition>,    // Tracks highest replicated entry for each follower
-
     // Server identification and role
     pub state: State,             // Current server role (Leader/Follower/Candidate)
     pub server_id: u64,           // Unique identifier for this server in the cluster
@@ -54,10 +55,9 @@ impl RaftState {
             current_term: 0,
             voted_for: None,
             logs: Vec::new(),
+            snapshot_position: LogPosition::new(0, 0),
             commit_position: LogPosition::new(0, 0),
             last_applied: LogPosition::new(0, 0),
-            next_position: Vec::new(),
-            match_position: Vec::new(),
             state: State::Follower,
             server_id,
         }
@@ -65,25 +65,169 @@ impl RaftState {
 
     /// Returns the index of the last log entry
     /// 
-    /// Returns 0 if the log is empty, otherwise returns
-    /// the index of the most recent entry in the log
+    /// Returns the index of the most recent entry in the log, or the last index
+    /// covered by the snapshot if no entry follows it (0 for an empty log)
     pub fn last_log_index(&self) -> u64 {
-        if self.logs.is_empty() {
-            0
-        } else {
-            self.logs.last().unwrap().last_log_index()
-        }
+        self.snapshot_position.index + self.logs.iter().map(SegmentLog::last_log_index).sum::<u64>()
     }
 
     /// Returns the term of the last log entry
     /// 
-    /// Returns 0 if the log is empty, otherwise returns
-    /// the term of the most recent entry in the log
+    /// Returns the term of the most recent entry in the log, or the term of the
+    /// last entry covered by the snapshot if no entry follows it (0 for an empty log)
     pub fn last_log_term(&self) -> u64 {
-        if self.logs.is_empty() {
-            0
+        match self.logs.last() {
+            Some(segment) => segment.term,
+            None => self.snapshot_position.term,
+        }
+    }
+
+    /// Returns whether a log ending at the given position is at least as
+    /// up-to-date as this server's log
+    ///
+    /// Logs are compared by the term of their last entries first; if the terms
+    /// are equal, the longer log is more up-to-date (Raft paper, section 5.4.1).
+    ///
+    /// # Arguments
+    /// * `last_log_index` - Index of the last entry of the other log
+    /// * `last_log_term` - Term of the last entry of the other log
+    pub fn is_log_up_to_date(&self, last_log_index: u64, last_log_term: u64) -> bool {
+        (last_log_term, last_log_index) >= (self.last_log_term(), self.last_log_index())
+    }
+
+    /// Returns the term of the entry at `index`
+    ///
+    /// Index 0 is the empty prefix of every log and has term 0. The term of the
+    /// last entry covered by the snapshot is known even though the entry itself
+    /// was discarded.
+    ///
+    /// # Returns
+    /// * `Some(term)` if the log contains `index`
+    /// * `None` if `index` is past the end of the log or was compacted
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_position.index {
+            return Some(self.snapshot_position.term);
+        }
+        self.locate(index).map(|(segment, _)| self.logs[segment].term)
+    }
+
+    /// Returns the index of the first entry of the same term as the entry at
+    /// `index` that the log still holds, if it holds the entry at `index`
+    pub fn first_index_of_term(&self, index: u64) -> Option<u64> {
+        let (mut segment, _) = self.locate(index)?;
+        let term = self.logs[segment].term;
+        while segment > 0 && self.logs[segment - 1].term == term {
+            segment -= 1;
+        }
+        let preceding: u64 = self.logs[..segment].iter().map(SegmentLog::last_log_index).sum();
+        Some(self.snapshot_position.index + preceding + 1)
+    }
+
+    /// Returns the index of the last entry of `term`, if the log or the
+    /// snapshot's last entry is of that term
+    pub fn last_index_of_term(&self, term: u64) -> Option<u64> {
+        let mut last_index = self.last_log_index();
+        for segment in self.logs.iter().rev() {
+            if segment.term == term {
+                return Some(last_index);
+            }
+            // Terms only increase along the log
+            if segment.term < term {
+                return None;
+            }
+            last_index -= segment.last_log_index();
+        }
+        (self.snapshot_position.term == term).then_some(self.snapshot_position.index)
+    }
+
+    /// Returns the entry at `index`, if the log contains it and it was not compacted
+    pub fn entry_at(&self, index: u64) -> Option<&LogEntry> {
+        self.locate(index)
+            .and_then(|(segment, offset)| self.logs[segment].log_at(offset))
+    }
+
+    /// Returns up to `max_entries` consecutive entries starting at `index`,
+    /// holding no more than `max_bytes` of payload unless the first entry alone does
+    ///
+    /// # Returns
+    /// The entries, possibly of several terms; the list is empty if `index` is
+    /// past the end of the log or was compacted
+    pub fn entries_from(&self, index: u64, max_entries: usize, max_bytes: u64) -> Vec<LogEntry> {
+        let Some((first_segment, offset)) = self.locate(index) else {
+            return Vec::new();
+        };
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        let start = offset as usize - 1;
+        let following = self.logs[first_segment..].iter().flat_map(|segment| &segment.entries).skip(start);
+        for entry in following.take(max_entries) {
+            bytes += entry.len() as u64;
+            if bytes > max_bytes && !entries.is_empty() {
+                break;
+            }
+            entries.push(entry.clone());
+        }
+        entries
+    }
+
+    /// Appends entries at the end of the log, grouping them by term
+    ///
+    /// The entries must directly follow the last entry of the log.
+    pub fn append_entries(&mut self, entries: Vec<LogEntry>) {
+        debug_assert!(entries.first().is_none_or(|entry| entry.index == self.last_log_index() + 1));
+        for entry in entries {
+            match self.logs.last_mut() {
+                Some(segment) if segment.term == entry.term => segment.entries.push(entry),
+                _ => self.logs.push(SegmentLog::new(entry.term, vec![entry])),
+            }
+        }
+    }
+
+    /// Removes the entry at `index` and every entry after it
+    pub fn truncate_from(&mut self, index: u64) {
+        if let Some((segment, offset)) = self.locate(index) {
+            self.logs[segment].entries.truncate(offset as usize - 1);
+            let keep = if self.logs[segment].entries.is_empty() { segment } else { segment + 1 };
+            self.logs.truncate(keep);
+        }
+    }
+
+    /// Discards the entries up to `position`, which a snapshot now covers
+    ///
+    /// If the log does not contain `position` (a snapshot received from the
+    /// leader can be ahead of it or disagree with it), the whole log is discarded.
+    pub fn compact_to(&mut self, position: LogPosition) {
+        if position.index <= self.snapshot_position.index {
+            return;
+        }
+        if self.term_at(position.index) == Some(position.term) {
+            let mut discarded = position.index - self.snapshot_position.index;
+            while discarded > 0 {
+                let segment = &mut self.logs[0];
+                if segment.last_log_index() <= discarded {
+                    discarded -= segment.last_log_index();
+                    self.logs.remove(0);
+                } else {
+                    segment.entries.drain(..discarded as usize);
+                    discarded = 0;
+                }
+            }
         } else {
-            self.logs.last().unwrap().term
+            self.logs.clear();
+        }
+        self.snapshot_position = position;
+    }
+
+    /// Finds the segment holding `index` and the entry's 1-based offset inside it
+    fn locate(&self, index: u64) -> Option<(usize, u64)> {
+        let mut first_index = self.snapshot_position.index + 1;
+        for (segment, log) in self.logs.iter().enumerate() {
+            let len = log.last_log_index();
+            if index >= first_index && index < first_index + len {
+                return Some((segment, index - first_index + 1));
+            }
+            first_index += len;
         }
+        None
     }
 }
@@ -0,0 +1,77 @@
+//! Application state replicated through the Raft log.
+
+use std::error::Error;
+
+/// The replicated application, fed with committed commands
+///
+/// Every server applies the same committed entries in the same order, so an
+/// implementation must be deterministic: the result of `apply` may only depend
+/// on the current state and the command being applied.
+pub trait StateMachine: Send {
+    /// Applies a committed command to the state machine
+    ///
+    /// # Arguments
+    /// * `index` - Log index of the command; successive calls receive increasing indexes
+    /// * `command` - The command bytes, as proposed by the client
+    ///
+    /// # Returns
+    /// The application's response, handed back to the client that proposed the
+    /// command when it was proposed through this server
+    fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8>;
+
+    /// Answers a read-only query against the current state
+    ///
+    /// Queries are not written to the log: `RaftNode::read` only calls this once
+    /// every entry committed when the read was received has been applied, so
+    /// the answer reflects all writes that completed before the read started.
+    fn query(&self, query: &[u8]) -> Vec<u8>;
+
+    /// Serializes the whole state, reflecting every command applied so far
+    ///
+    /// The snapshot replaces the log prefix up to the last applied entry, so it
+    /// must capture everything a later `restore` needs to rebuild the state.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replaces the whole state with one produced by `snapshot`
+    ///
+    /// # Returns
+    /// * `Err` if `snapshot` cannot be decoded; the server cannot continue then
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Index and bytes of every applied command, in order
+    type Applied = Vec<(u64, Vec<u8>)>;
+
+    /// Records every applied command and answers with the command itself;
+    /// queries are answered with the last applied command
+    #[derive(Default, Clone)]
+    pub(crate) struct EchoStateMachine {
+        pub(crate) applied: Arc<Mutex<Applied>>,
+    }
+
+    impl StateMachine for EchoStateMachine {
+        fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8> {
+            self.applied.lock().unwrap().push((index, command.to_vec()));
+            command.to_vec()
+        }
+
+        fn query(&self, _query: &[u8]) -> Vec<u8> {
+            self.applied.lock().unwrap().last().map(|(_, command)| command.clone()).unwrap_or_default()
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            serde_json::to_vec(&*self.applied.lock().unwrap()).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+            *self.applied.lock().unwrap() = serde_json::from_slice(snapshot)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::configuration::Configuration;
+use crate::log::{LogEntry, LogPosition, SegmentLog};
+
+use super::raft_storage::{into_segments, HardState, PendingSync, RaftStorage, Snapshot, StorageError};
+use super::wal::{DurabilityPolicy, Wal, WalOptions};
+
+const HARD_STATE_FILE: &str = "hard_state.json";
+const WAL_DIRECTORY: &str = "wal";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// Size of the header preceding the configuration in the snapshot file: last
+/// included term and index, and configuration length
+const SNAPSHOT_HEADER_SIZE: usize = 8 + 8 + 4;
+
+/// `RaftStorage` persisting to files in a directory
+///
+/// The hard state is a small JSON file replaced atomically on every save. The log
+/// is a segmented write-ahead log (see `Wal`) in the `wal` subdirectory. Appended
+/// entries are fsynced according to the WAL's `DurabilityPolicy`; the hard state
+/// and snapshot are fsynced on every save unless the policy is `DurabilityPolicy::None`.
+///
+/// The latest snapshot is a file of `[term: u64][index: u64][configuration
+/// length: u32][configuration JSON][data]`, replaced atomically like the hard state.
+pub struct FileStorage {
+    directory: PathBuf,
+    hard_state: HardState,
+    fsync: bool,           // Whether the hard state and snapshot files are fsynced
+    snapshot_index: u64,   // Last entry covered by the saved snapshot, 0 without snapshot
+    wal: Wal,
+}
+
+impl FileStorage {
+    /// Opens the storage in `directory` with the default WAL options, creating
+    /// the directory if needed
+    pub fn open(directory: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open_with_options(directory, WalOptions::default())
+    }
+
+    /// Opens the storage in `directory`, creating the directory if needed
+    ///
+    /// A record cut short by a crash in the middle of an append is discarded;
+    /// a damaged record anywhere else fails with `StorageError::Wal`.
+    pub fn open_with_options(directory: impl AsRef<Path>, wal_options: WalOptions) -> Result<Self, StorageError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let hard_state = match fs::read(directory.join(HARD_STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|error| StorageError::Corrupted(format!("invalid hard state: {}", error)))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(error) => return Err(error.into()),
+        };
+        let fsync = wal_options.durability != DurabilityPolicy::None;
+        let mut wal = Wal::open(directory.join(WAL_DIRECTORY), wal_options)?;
+
+        // Finishes a compaction interrupted after its snapshot was saved
+        let snapshot_index = match read_snapshot(&directory)? {
+            Some(snapshot) => {
+                wal.compact(snapshot.last_included.index)?;
+                snapshot.last_included.index
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            directory,
+            hard_state,
+            fsync,
+            snapshot_index,
+            wal,
+        })
+    }
+}
+
+impl RaftStorage for FileStorage {
+    fn load_hard_state(&self) -> Result<HardState, StorageError> {
+        Ok(self.hard_state.clone())
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<(), StorageError> {
+        let bytes = serde_json::to_vec(hard_state)
+            .map_err(|error| StorageError::Corrupted(error.to_string()))?;
+        write_atomically(&self.directory, HARD_STATE_FILE, &bytes, self.fsync)?;
+        self.hard_state = hard_state.clone();
+        Ok(())
+    }
+
+    fn first_index(&self) -> u64 {
+        self.wal.first_index().max(self.snapshot_index + 1)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.wal.last_index()
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> Result<(), StorageError> {
+        Ok(self.wal.append(entries)?)
+    }
+
+    fn append_deferred(&mut self, entries: &[LogEntry]) -> Result<(), StorageError> {
+        Ok(self.wal.append_deferred(entries)?)
+    }
+
+    fn durable_index(&self) -> u64 {
+        self.wal.durable_index()
+    }
+
+    fn sync(&mut self) -> Result<(), StorageError> {
+        Ok(self.wal.sync()?)
+    }
+
+    fn start_sync(&mut self) -> Result<Option<PendingSync>, StorageError> {
+        let sync = self.wal.start_sync()?;
+        Ok(sync.map(|sync| PendingSync::new(move || Ok(sync.run()?))))
+    }
+
+    fn finish_sync(&mut self) {
+        self.wal.finish_sync()
+    }
+
+    fn sync_deadline(&self) -> Option<Instant> {
+        self.wal.sync_deadline()
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<(), StorageError> {
+        Ok(self.wal.truncate(index)?)
+    }
+
+    fn entries(&self, low: u64, high: u64) -> Result<Vec<SegmentLog>, StorageError> {
+        Ok(into_segments(self.wal.read(low.max(self.first_index()), high)?))
+    }
+
+    fn log_size(&self) -> u64 {
+        self.wal.size()
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let configuration = snapshot.configuration.encode();
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + configuration.len() + snapshot.data.len());
+        bytes.extend_from_slice(&snapshot.last_included.term.to_be_bytes());
+        bytes.extend_from_slice(&snapshot.last_included.index.to_be_bytes());
+        bytes.extend_from_slice(&(configuration.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&configuration);
+        bytes.extend_from_slice(&snapshot.data);
+        write_atomically(&self.directory, SNAPSHOT_FILE, &bytes, self.fsync)
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Snapshot>, StorageError> {
+        read_snapshot(&self.directory)
+    }
+
+    fn compact(&mut self, index: u64) -> Result<(), StorageError> {
+        self.wal.compact(index)?;
+        self.snapshot_index = self.snapshot_index.max(index);
+        Ok(())
+    }
+}
+
+fn read_snapshot(directory: &Path) -> Result<Option<Snapshot>, StorageError> {
+    let bytes = match fs::read(directory.join(SNAPSHOT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    if bytes.len() < SNAPSHOT_HEADER_SIZE {
+        return Err(StorageError::Corrupted("snapshot file is too short".to_string()));
+    }
+    let term = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+    let index = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+    let configuration_length = u32::from_be_bytes(bytes[16..20].try_into().unwrap()) as usize;
+    let data_offset = SNAPSHOT_HEADER_SIZE + configuration_length;
+    let configuration = bytes
+        .get(SNAPSHOT_HEADER_SIZE..data_offset)
+        .and_then(Configuration::decode)
+        .ok_or_else(|| StorageError::Corrupted("invalid snapshot configuration".to_string()))?;
+    Ok(Some(Snapshot {
+        last_included: LogPosition::new(term, index),
+        configuration,
+        data: bytes[data_offset..].to_vec(),
+    }))
+}
+
+/// Replaces `directory/name` with `bytes` so that a crash leaves either the old
+/// or the new content, never a mix of both
+///
+/// Without `fsync` the replacement is still atomic for the running process but
+/// may be lost if the machine crashes.
+fn write_atomically(directory: &Path, name: &str, bytes: &[u8], fsync: bool) -> Result<(), StorageError> {
+    let temporary = directory.join(format!("{}.tmp", name));
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    fs::rename(&temporary, directory.join(name))?;
+    if fsync {
+        File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::wal::{segment_file_name, SEGMENT_EXTENSION};
+
+    #[test]
+    fn reopened_storage_keeps_hard_state_and_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut storage = FileStorage::open(directory.path()).unwrap();
+            storage.save_hard_state(&HardState { current_term: 3, voted_for: Some(2) }).unwrap();
+            storage.append(&[LogEntry::command(1, 1, vec![1]), LogEntry::command(1, 2, vec![2])]).unwrap();
+            storage.append(&[LogEntry::noop(3, 3)]).unwrap();
+        }
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(storage.load_hard_state().unwrap(), HardState { current_term: 3, voted_for: Some(2) });
+        assert_eq!(storage.last_index(), 3);
+
+        let segments = storage.entries(1, 4).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!((segments[0].term, segments[0].entries.clone()), (1, vec![LogEntry::command(1, 1, vec![1]), LogEntry::command(1, 2, vec![2])]));
+        assert_eq!((segments[1].term, segments[1].entries.clone()), (3, vec![LogEntry::noop(3, 3)]));
+    }
+
+    #[test]
+    fn truncated_entries_are_gone_after_reopen() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut storage = FileStorage::open(directory.path()).unwrap();
+            storage.append(&[LogEntry::command(1, 1, vec![1]), LogEntry::command(1, 2, vec![2]), LogEntry::command(1, 3, vec![3])]).unwrap();
+            storage.truncate(2).unwrap();
+            storage.append(&[LogEntry::command(2, 2, vec![4])]).unwrap();
+        }
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let segments = storage.entries(1, 3).unwrap();
+        assert_eq!((segments[0].term, segments[0].entries.clone()), (1, vec![LogEntry::command(1, 1, vec![1])]));
+        assert_eq!((segments[1].term, segments[1].entries.clone()), (2, vec![LogEntry::command(2, 2, vec![4])]));
+    }
+
+    #[test]
+    fn compacted_log_restarts_after_the_snapshot() {
+        let directory = tempfile::tempdir().unwrap();
+        let snapshot = Snapshot {
+            last_included: LogPosition::new(1, 2),
+            configuration: Configuration::new([1, 2, 3]),
+            data: vec![7],
+        };
+        {
+            let mut storage = FileStorage::open(directory.path()).unwrap();
+            storage.append(&[LogEntry::command(1, 1, vec![1]), LogEntry::command(1, 2, vec![2]), LogEntry::command(1, 3, vec![3])]).unwrap();
+            storage.save_snapshot(&snapshot).unwrap();
+            storage.compact(2).unwrap();
+            assert_eq!((storage.first_index(), storage.last_index()), (3, 3));
+        }
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(storage.load_snapshot().unwrap(), Some(snapshot));
+        assert_eq!((storage.first_index(), storage.last_index()), (3, 3));
+        assert_eq!(storage.entries(1, 4).unwrap()[0].entries, vec![LogEntry::command(1, 3, vec![3])]);
+    }
+
+    #[test]
+    fn incomplete_trailing_record_is_discarded() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut storage = FileStorage::open(directory.path()).unwrap();
+            storage.append(&[LogEntry::command(1, 1, vec![1, 1]), LogEntry::command(1, 2, vec![2, 2])]).unwrap();
+        }
+        let segment_path = directory.path().join(WAL_DIRECTORY).join(segment_file_name(1, SEGMENT_EXTENSION));
+        let size = fs::metadata(&segment_path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&segment_path).unwrap().set_len(size - 1).unwrap();
+
+        let mut storage = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(storage.last_index(), 1);
+        storage.append(&[LogEntry::command(1, 2, vec![3])]).unwrap();
+        assert_eq!(storage.entries(2, 3).unwrap()[0].entries, vec![LogEntry::command(1, 2, vec![3])]);
+    }
+}
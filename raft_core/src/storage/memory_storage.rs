@@ -0,0 +1,91 @@
+use crate::log::{LogEntry, SegmentLog};
+
+use super::raft_storage::{into_segments, HardState, RaftStorage, Snapshot, StorageError};
+
+/// Volatile `RaftStorage` keeping everything in memory
+///
+/// Nothing survives a restart, so this is only suitable for tests and
+/// throw-away clusters.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    hard_state: HardState,
+    snapshot: Option<Snapshot>,
+    first_index: u64,                // Index of the first element of `entries`
+    entries: Vec<LogEntry>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self {
+            hard_state: HardState::default(),
+            snapshot: None,
+            first_index: 1,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl RaftStorage for MemoryStorage {
+    fn load_hard_state(&self) -> Result<HardState, StorageError> {
+        Ok(self.hard_state.clone())
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<(), StorageError> {
+        self.hard_state = hard_state.clone();
+        Ok(())
+    }
+
+    fn first_index(&self) -> u64 {
+        self.first_index
+    }
+
+    fn last_index(&self) -> u64 {
+        self.first_index + self.entries.len() as u64 - 1
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> Result<(), StorageError> {
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<(), StorageError> {
+        self.entries.truncate(index.saturating_sub(self.first_index) as usize);
+        Ok(())
+    }
+
+    fn entries(&self, low: u64, high: u64) -> Result<Vec<SegmentLog>, StorageError> {
+        let low = low.max(self.first_index) - self.first_index;
+        let high = (high.max(self.first_index) - self.first_index).min(self.entries.len() as u64);
+        let entries = self.entries.get(low as usize..high as usize).unwrap_or_default();
+        Ok(into_segments(entries.iter().cloned()))
+    }
+
+    fn log_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.len() as u64).sum()
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Snapshot>, StorageError> {
+        Ok(self.snapshot.clone())
+    }
+
+    fn compact(&mut self, index: u64) -> Result<(), StorageError> {
+        if index < self.first_index {
+            return Ok(());
+        }
+        let discarded = (index - self.first_index + 1).min(self.entries.len() as u64);
+        self.entries.drain(..discarded as usize);
+        self.first_index = index + 1;
+        Ok(())
+    }
+}
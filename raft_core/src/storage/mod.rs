@@ -0,0 +1,4 @@
+pub mod file_storage;
+pub mod memory_storage;
+pub mod raft_storage;
+pub mod wal;
@@ -0,0 +1,185 @@
+//! Stable storage for the persistent part of the Raft state.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::wal::WalError;
+use crate::configuration::Configuration;
+use crate::log::{LogEntry, LogPosition, SegmentLog};
+
+/// The persistent state of a server besides its log
+///
+/// Must be durable before the server answers an RPC that depends on it: a
+/// server that forgets its vote after a crash could vote twice in a term.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub current_term: u64,        // Latest term the server has seen
+    pub voted_for: Option<u64>,   // Candidate that received the vote in current_term
+}
+
+/// State machine snapshot replacing the log prefix up to `last_included`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last_included: LogPosition,   // Last entry whose command is reflected in `data`
+    pub configuration: Configuration, // Cluster configuration as of `last_included`
+    pub data: Vec<u8>,                // Output of `StateMachine::snapshot`
+}
+
+/// Errors raised by a `RaftStorage`
+#[derive(Debug)]
+pub enum StorageError {
+    /// The underlying I/O operation failed
+    Io(io::Error),
+    /// Stored data could not be decoded
+    Corrupted(String),
+    /// The write-ahead log failed or is corrupted
+    Wal(WalError),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(error) => write!(f, "storage I/O error: {}", error),
+            StorageError::Corrupted(reason) => write!(f, "storage is corrupted: {}", reason),
+            StorageError::Wal(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Io(error) => Some(error),
+            StorageError::Corrupted(_) => None,
+            StorageError::Wal(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        StorageError::Io(error)
+    }
+}
+
+impl From<WalError> for StorageError {
+    fn from(error: WalError) -> Self {
+        StorageError::Wal(error)
+    }
+}
+
+/// An fsync started by `RaftStorage::start_sync`, run without access to the
+/// storage so that a slow disk does not hold up the node
+pub struct PendingSync(Box<dyn FnOnce() -> Result<(), StorageError> + Send>);
+
+impl PendingSync {
+    /// Wraps the operation making the entries covered by the sync durable
+    pub fn new(sync: impl FnOnce() -> Result<(), StorageError> + Send + 'static) -> Self {
+        Self(Box::new(sync))
+    }
+
+    /// Runs the fsync; the storage must then be told with `finish_sync`
+    pub fn run(self) -> Result<(), StorageError> {
+        (self.0)()
+    }
+}
+
+/// Stable storage for the hard state and the log of a Raft server
+///
+/// `save_hard_state` and `truncate` must be durable when they return
+/// successfully: the node sends the replies that depend on them right
+/// afterwards. Appended entries may become durable later; the node holds the
+/// replies acknowledging them until `durable_index` covers them.
+pub trait RaftStorage: Send {
+    /// Loads the hard state, or the default state if none was ever saved
+    fn load_hard_state(&self) -> Result<HardState, StorageError>;
+
+    /// Durably replaces the hard state
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<(), StorageError>;
+
+    /// Index of the first stored entry
+    fn first_index(&self) -> u64;
+
+    /// Index of the last stored entry, or `first_index() - 1` if the log is empty
+    fn last_index(&self) -> u64;
+
+    /// Appends entries right after the last stored entry; the index of the first
+    /// one is `last_index() + 1`
+    fn append(&mut self, entries: &[LogEntry]) -> Result<(), StorageError>;
+
+    /// Appends entries like `append`, but leaves them to a later `sync` even
+    /// if `append` would make them durable before returning
+    ///
+    /// Lets the leader send new entries to its followers while its own copy is
+    /// still being written; `sync_deadline` must then tell when `sync` is due.
+    fn append_deferred(&mut self, entries: &[LogEntry]) -> Result<(), StorageError> {
+        self.append(entries)
+    }
+
+    /// Index of the last entry that survives a crash
+    fn durable_index(&self) -> u64 {
+        self.last_index()
+    }
+
+    /// Makes every appended entry durable
+    fn sync(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Starts making every appended entry durable like `sync`, but hands the
+    /// fsync back to run on another thread, after which `finish_sync` is called
+    ///
+    /// Returns `None` if there is nothing left to run, including while an
+    /// earlier `PendingSync` has not finished. The default syncs in place.
+    fn start_sync(&mut self) -> Result<Option<PendingSync>, StorageError> {
+        self.sync().map(|()| None)
+    }
+
+    /// Records that the `PendingSync` returned by the last `start_sync` ran
+    /// successfully
+    fn finish_sync(&mut self) {}
+
+    /// Time by which `sync` should be called for appended entries that are not
+    /// durable yet, or `None` if there are none
+    fn sync_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Removes the entry at `index` and every entry after it
+    fn truncate(&mut self, index: u64) -> Result<(), StorageError>;
+
+    /// Reads the entries in `[low, high)`, grouped by term
+    fn entries(&self, low: u64, high: u64) -> Result<Vec<SegmentLog>, StorageError>;
+
+    /// Size in bytes of the stored entries
+    fn log_size(&self) -> u64;
+
+    /// Durably replaces the stored snapshot
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), StorageError>;
+
+    /// Loads the latest saved snapshot, if any
+    fn load_snapshot(&self) -> Result<Option<Snapshot>, StorageError>;
+
+    /// Discards the entries up to and including `index`, which a saved snapshot
+    /// covers
+    ///
+    /// When `index` is past the last entry the whole log is discarded and the
+    /// next appended entry gets index `index + 1`.
+    fn compact(&mut self, index: u64) -> Result<(), StorageError>;
+}
+
+/// Groups consecutive entries of the same term into segments
+pub(crate) fn into_segments(entries: impl IntoIterator<Item = LogEntry>) -> Vec<SegmentLog> {
+    let mut segments: Vec<SegmentLog> = Vec::new();
+    for entry in entries {
+        match segments.last_mut() {
+            Some(segment) if segment.term == entry.term => segment.entries.push(entry),
+            _ => segments.push(SegmentLog::new(entry.term, vec![entry])),
+        }
+    }
+    segments
+}
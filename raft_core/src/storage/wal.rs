@@ -0,0 +1,952 @@
+//! Segmented write-ahead log holding the Raft log entries of `FileStorage`.
+//!
+//! The log is split into segment files named after the index of their first
+//! entry. A segment is a sequence of records:
+//!
+//! `[payload length: u32][crc32: u32][term: u64][index: u64][payload]`
+//!
+//! where the payload is the entry, `[kind: u8][entry payload]`, and the CRC
+//! covers the length, the term, the index and the payload. Next to every
+//! segment, an index file holds the offset of each of its records so that an
+//! entry can be located by its log index without scanning the segment.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::log::{EntryKind, LogEntry};
+
+pub(crate) const SEGMENT_EXTENSION: &str = "wal";
+const INDEX_EXTENSION: &str = "idx";
+
+/// Size of the header preceding every record: payload length, CRC, term and index
+const RECORD_HEADER_SIZE: usize = 4 + 4 + 8 + 8;
+
+/// Size of an index file slot: the offset of one record in its segment
+const INDEX_SLOT_SIZE: u64 = 8;
+
+/// Default size above which a new segment is started
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// When appended entries are fsynced, trading append latency against the
+/// number of entries a crash can lose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityPolicy {
+    /// Every append is fsynced before it returns
+    EveryAppend,
+    /// Appends are fsynced in groups, once `max_entries` entries are waiting or
+    /// the oldest of them has waited for `max_delay`, whichever comes first
+    GroupCommit { max_entries: u64, max_delay: Duration },
+    /// Nothing is ever fsynced; for test clusters only, a crash of the machine
+    /// can lose acknowledged entries and votes
+    None,
+}
+
+/// Tuning of a `Wal`
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    pub max_segment_size: u64,           // A segment is sealed once the next record would make it larger
+    pub durability: DurabilityPolicy,    // When appended entries are fsynced
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            durability: DurabilityPolicy::EveryAppend,
+        }
+    }
+}
+
+/// Errors raised by a `Wal`
+#[derive(Debug)]
+pub enum WalError {
+    /// The underlying I/O operation failed
+    Io(io::Error),
+    /// A record that is not the last one written is invalid: the log cannot be
+    /// trusted past `offset` of `segment`
+    Corrupted {
+        segment: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::Io(error) => write!(f, "WAL I/O error: {}", error),
+            WalError::Corrupted { segment, offset, reason } => {
+                write!(f, "WAL segment {} is corrupted at offset {}: {}", segment.display(), offset, reason)
+            }
+        }
+    }
+}
+
+impl Error for WalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalError::Io(error) => Some(error),
+            WalError::Corrupted { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for WalError {
+    fn from(error: io::Error) -> Self {
+        WalError::Io(error)
+    }
+}
+
+/// One segment file and its index file
+struct Segment {
+    first_index: u64,    // Log index of the first record
+    entry_count: u64,    // Number of records
+    size: u64,           // Offset right after the last record
+    log_path: PathBuf,
+    log: File,
+    index_path: PathBuf,
+    index: File,
+}
+
+impl Segment {
+    /// Index following the last record of the segment
+    fn end_index(&self) -> u64 {
+        self.first_index + self.entry_count
+    }
+
+    /// Reads the offset of the record at `index` from the index file
+    fn offset_of(&self, index: u64) -> Result<u64, WalError> {
+        let mut slot = [0; INDEX_SLOT_SIZE as usize];
+        let mut reader = &self.index;
+        reader.seek(SeekFrom::Start((index - self.first_index) * INDEX_SLOT_SIZE))?;
+        reader.read_exact(&mut slot)?;
+        Ok(u64::from_be_bytes(slot))
+    }
+
+    fn corrupted(&self, offset: u64, reason: impl Into<String>) -> WalError {
+        WalError::Corrupted {
+            segment: self.log_path.clone(),
+            offset,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// An fsync of the last segment started by `Wal::start_sync`
+pub struct WalSync {
+    log: File,
+}
+
+impl WalSync {
+    /// Fsyncs the entries written to the segment so far
+    pub fn run(self) -> Result<(), WalError> {
+        Ok(self.log.sync_data()?)
+    }
+}
+
+/// Append-only log of entries stored in size-bounded segment files
+///
+/// Appended entries are fsynced according to the `DurabilityPolicy`;
+/// `durable_index` tells how far the log is known to survive a crash. Index
+/// files are derived from the segments and are rebuilt on open whenever they
+/// disagree with them, so they are never fsynced.
+pub struct Wal {
+    directory: PathBuf,
+    options: WalOptions,
+    segments: Vec<Segment>,           // Ordered by first index, contiguous
+    next_index: u64,                  // Index the next appended entry receives
+    durable_index: u64,               // Last entry known to be on stable storage
+    unsynced_since: Option<Instant>,  // When the oldest entry after durable_index was appended
+    sync_in_flight: Option<u64>,      // Last entry made durable by the running `WalSync`, if any
+    compacted_size: u64,              // Bytes of compacted records left at the start of the first segment
+}
+
+impl Wal {
+    /// Opens the log in `directory`, creating the directory if needed
+    ///
+    /// Every record is checked. An incomplete or invalid record at the very end
+    /// of the last segment is the trace of a write interrupted by a crash and
+    /// is truncated; any other invalid record is reported as
+    /// `WalError::Corrupted`.
+    pub fn open(directory: impl AsRef<Path>, options: WalOptions) -> Result<Self, WalError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let mut first_indexes = Vec::new();
+        for dir_entry in fs::read_dir(&directory)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                Some(first_index) => first_indexes.push(first_index),
+                None => log::warn!("Ignoring unexpected file {} in the WAL directory", path.display()),
+            }
+        }
+        first_indexes.sort_unstable();
+
+        let mut segments: Vec<Segment> = Vec::with_capacity(first_indexes.len());
+        for (position, &first_index) in first_indexes.iter().enumerate() {
+            let is_last = position + 1 == first_indexes.len();
+            let segment = load_segment(&directory, first_index, is_last)?;
+            if let Some(previous) = segments.last()
+                && previous.end_index() != first_index
+            {
+                return Err(segment.corrupted(
+                    0,
+                    format!("expected the segment to start at index {}", previous.end_index()),
+                ));
+            }
+            segments.push(segment);
+        }
+
+        let next_index = segments.last().map_or(1, Segment::end_index);
+        Ok(Self {
+            directory,
+            options,
+            segments,
+            next_index,
+            durable_index: next_index - 1,
+            unsynced_since: None,
+            sync_in_flight: None,
+            compacted_size: 0,
+        })
+    }
+
+    /// Index of the first stored entry
+    pub fn first_index(&self) -> u64 {
+        self.segments.first().map_or(self.next_index, |segment| segment.first_index)
+    }
+
+    /// Index of the last stored entry, or `first_index() - 1` if the log is empty
+    pub fn last_index(&self) -> u64 {
+        self.next_index - 1
+    }
+
+    /// Size in bytes of the records that were not compacted
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum::<u64>() - self.compacted_size
+    }
+
+    /// Index of the last entry known to be on stable storage
+    pub fn durable_index(&self) -> u64 {
+        self.durable_index
+    }
+
+    /// Time by which `sync` must be called to honour the `DurabilityPolicy`, if
+    /// entries are waiting to be fsynced
+    ///
+    /// Entries appended with `append_deferred` that the policy would already
+    /// have fsynced are due right away.
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        let appended_at = self.unsynced_since?;
+        match self.options.durability {
+            DurabilityPolicy::EveryAppend => Some(appended_at),
+            DurabilityPolicy::GroupCommit { max_entries, .. } if self.last_index() - self.durable_index >= max_entries => {
+                Some(appended_at)
+            }
+            DurabilityPolicy::GroupCommit { max_delay, .. } => Some(appended_at + max_delay),
+            DurabilityPolicy::None => None,
+        }
+    }
+
+    /// Appends entries after the last stored entry, starting new segments as
+    /// the current one fills up; the index of the first entry is `last_index() + 1`
+    ///
+    /// The entries are fsynced before returning or later, depending on the
+    /// `DurabilityPolicy`.
+    pub fn append(&mut self, entries: &[LogEntry]) -> Result<(), WalError> {
+        self.write(entries)?;
+        match self.options.durability {
+            DurabilityPolicy::EveryAppend => self.sync(),
+            DurabilityPolicy::GroupCommit { max_entries, .. } => {
+                if self.last_index() - self.durable_index >= max_entries {
+                    self.sync()?;
+                }
+                Ok(())
+            }
+            DurabilityPolicy::None => Ok(()),
+        }
+    }
+
+    /// Appends entries like `append`, but never fsyncs them before returning:
+    /// `sync_deadline` tells when `sync` is due
+    pub fn append_deferred(&mut self, entries: &[LogEntry]) -> Result<(), WalError> {
+        self.write(entries)
+    }
+
+    /// Writes the records of `entries` after the last stored entry, starting
+    /// new segments as the current one fills up, without fsyncing them
+    fn write(&mut self, entries: &[LogEntry]) -> Result<(), WalError> {
+        let mut records = Vec::new();
+        let mut slots = Vec::new();
+        let mut index = self.next_index;
+        for entry in entries {
+            debug_assert_eq!(entry.index, index);
+            let record_size = (RECORD_HEADER_SIZE + 1 + entry.len()) as u64;
+            // Sizes include the records of this call that are not written yet
+            let segment_full = match self.segments.last() {
+                Some(segment) => {
+                    index > segment.first_index
+                        && segment.size + records.len() as u64 + record_size > self.options.max_segment_size
+                }
+                None => true,
+            };
+            if segment_full {
+                self.write_to_last_segment(&records, &slots, index)?;
+                self.seal_last_segment()?;
+                records.clear();
+                slots.clear();
+                self.create_segment(index)?;
+            }
+
+            let size = self.segments.last().map_or(0, |segment| segment.size);
+            slots.extend_from_slice(&(size + records.len() as u64).to_be_bytes());
+            encode_record(&mut records, entry);
+            index += 1;
+        }
+        self.write_to_last_segment(&records, &slots, index)?;
+
+        if !self.fsync_enabled() {
+            self.durable_index = self.last_index();
+        } else if self.durable_index < self.last_index() {
+            self.unsynced_since.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    /// Fsyncs every appended entry
+    pub fn sync(&mut self) -> Result<(), WalError> {
+        // Earlier segments were fsynced when they were sealed
+        if self.durable_index < self.last_index()
+            && let Some(segment) = self.segments.last()
+        {
+            segment.log.sync_data()?;
+        }
+        self.durable_index = self.last_index();
+        self.unsynced_since = None;
+        Ok(())
+    }
+
+    /// Starts fsyncing every appended entry without holding the log: the
+    /// returned `WalSync` runs on its own, then `finish_sync` records it
+    ///
+    /// Returns `None` if every entry is durable or a `WalSync` is still running.
+    pub fn start_sync(&mut self) -> Result<Option<WalSync>, WalError> {
+        if self.sync_in_flight.is_some() || self.durable_index >= self.last_index() {
+            return Ok(None);
+        }
+        // Earlier segments were fsynced when they were sealed
+        let Some(segment) = self.segments.last() else {
+            return Ok(None);
+        };
+        let log = segment.log.try_clone()?;
+        self.sync_in_flight = Some(self.last_index());
+        self.unsynced_since = None;
+        Ok(Some(WalSync { log }))
+    }
+
+    /// Records that the `WalSync` returned by `start_sync` completed
+    pub fn finish_sync(&mut self) {
+        let Some(synced_index) = self.sync_in_flight.take() else {
+            return;
+        };
+        self.durable_index = self.durable_index.max(synced_index.min(self.last_index()));
+        if self.durable_index == self.last_index() {
+            self.unsynced_since = None;
+        }
+    }
+
+    /// Durably removes the entry at `index` and every entry after it
+    pub fn truncate(&mut self, index: u64) -> Result<(), WalError> {
+        if index >= self.next_index {
+            return Ok(());
+        }
+
+        let mut removed_files = false;
+        while let Some(segment) = self.segments.last() {
+            if segment.first_index < index {
+                break;
+            }
+            fs::remove_file(&segment.log_path)?;
+            fs::remove_file(&segment.index_path)?;
+            self.segments.pop();
+            removed_files = true;
+        }
+        if removed_files {
+            self.sync_directory()?;
+        }
+
+        let fsync = self.fsync_enabled();
+        if let Some(segment) = self.segments.last_mut() {
+            let offset = segment.offset_of(index)?;
+            segment.log.set_len(offset)?;
+            if fsync {
+                segment.log.sync_data()?;
+            }
+            segment.index.set_len((index - segment.first_index) * INDEX_SLOT_SIZE)?;
+            segment.entry_count = index - segment.first_index;
+            segment.size = offset;
+        }
+        self.next_index = index;
+        self.durable_index = self.durable_index.min(index - 1);
+        // Entries written again after the truncation may be missed by a running sync
+        if let Some(synced_index) = &mut self.sync_in_flight {
+            *synced_index = (*synced_index).min(index - 1);
+        }
+        if self.durable_index == self.last_index() {
+            self.unsynced_since = None;
+        }
+        Ok(())
+    }
+
+    /// Discards the entries up to and including `index` by removing the segments
+    /// that hold nothing else
+    ///
+    /// Compacted entries may remain at the start of the first segment; they are
+    /// still returned by `read`, so callers must not ask for them. When `index`
+    /// is past the last entry every segment is removed and the next appended
+    /// entry gets index `index + 1`.
+    pub fn compact(&mut self, index: u64) -> Result<(), WalError> {
+        let mut removed_files = false;
+        while let Some(segment) = self.segments.first() {
+            if segment.end_index() > index + 1 {
+                break;
+            }
+            fs::remove_file(&segment.log_path)?;
+            fs::remove_file(&segment.index_path)?;
+            self.segments.remove(0);
+            removed_files = true;
+        }
+        if removed_files {
+            self.sync_directory()?;
+        }
+
+        self.next_index = self.next_index.max(index + 1);
+        // Compacted entries are covered by a snapshot, whether they were synced or not
+        self.durable_index = self.durable_index.max(index);
+        if self.durable_index == self.last_index() {
+            self.unsynced_since = None;
+        }
+        self.compacted_size = match self.segments.first() {
+            Some(segment) if segment.first_index <= index => segment.offset_of(index + 1)?,
+            _ => 0,
+        };
+        Ok(())
+    }
+
+    /// Reads the entries in `[low, high)`
+    pub fn read(&self, low: u64, high: u64) -> Result<Vec<LogEntry>, WalError> {
+        let low = low.max(self.first_index());
+        let high = high.min(self.next_index);
+        let mut entries = Vec::with_capacity(high.saturating_sub(low) as usize);
+        for segment in &self.segments {
+            if segment.end_index() <= low || segment.first_index >= high {
+                continue;
+            }
+            let from = low.max(segment.first_index);
+            let to = high.min(segment.end_index());
+            let start = segment.offset_of(from)?;
+            let end = if to == segment.end_index() {
+                segment.size
+            } else {
+                segment.offset_of(to)?
+            };
+
+            let mut bytes = vec![0; (end - start) as usize];
+            let mut reader = &segment.log;
+            reader.seek(SeekFrom::Start(start))?;
+            reader.read_exact(&mut bytes)?;
+
+            let mut offset = 0;
+            for expected_index in from..to {
+                match decode_record(&bytes[offset..]) {
+                    DecodedRecord::Valid { term, index, payload, size } if index == expected_index => {
+                        let Some(entry) = decode_entry(term, index, payload) else {
+                            return Err(segment.corrupted(start + offset as u64, "invalid entry kind"));
+                        };
+                        entries.push(entry);
+                        offset += size;
+                    }
+                    _ => return Err(segment.corrupted(start + offset as u64, "invalid record on read")),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Writes encoded records to the last segment
+    ///
+    /// # Arguments
+    /// * `records` - Encoded records, following the current end of the segment
+    /// * `slots` - Index file slots of the records
+    /// * `next_index` - Index following the last of the records
+    fn write_to_last_segment(&mut self, records: &[u8], slots: &[u8], next_index: u64) -> Result<(), WalError> {
+        let Some(segment) = self.segments.last_mut() else {
+            return Ok(());
+        };
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        segment.log.seek(SeekFrom::Start(segment.size))?;
+        segment.log.write_all(records)?;
+        segment.index.seek(SeekFrom::Start(segment.entry_count * INDEX_SLOT_SIZE))?;
+        segment.index.write_all(slots)?;
+
+        segment.size += records.len() as u64;
+        segment.entry_count += slots.len() as u64 / INDEX_SLOT_SIZE;
+        self.next_index = next_index;
+        Ok(())
+    }
+
+    /// Fsyncs the last segment before a new one is started after it, so that
+    /// only the last segment ever holds entries that are not durable
+    fn seal_last_segment(&mut self) -> Result<(), WalError> {
+        let Some(segment) = self.segments.last() else {
+            return Ok(());
+        };
+        if self.fsync_enabled() {
+            segment.log.sync_data()?;
+            self.durable_index = segment.end_index() - 1;
+        }
+        Ok(())
+    }
+
+    /// Starts a new, empty segment whose first record will be `first_index`
+    fn create_segment(&mut self, first_index: u64) -> Result<(), WalError> {
+        let log_path = self.directory.join(segment_file_name(first_index, SEGMENT_EXTENSION));
+        let index_path = self.directory.join(segment_file_name(first_index, INDEX_EXTENSION));
+        let log = open_file(&log_path, true)?;
+        let index = open_file(&index_path, true)?;
+        self.sync_directory()?;
+
+        self.segments.push(Segment {
+            first_index,
+            entry_count: 0,
+            size: 0,
+            log_path,
+            log,
+            index_path,
+            index,
+        });
+        Ok(())
+    }
+
+    /// Makes the creation or removal of segment files durable
+    fn sync_directory(&self) -> Result<(), WalError> {
+        if self.fsync_enabled() {
+            File::open(&self.directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn fsync_enabled(&self) -> bool {
+        self.options.durability != DurabilityPolicy::None
+    }
+}
+
+/// Name of the segment or index file whose first record is `first_index`
+pub(crate) fn segment_file_name(first_index: u64, extension: &str) -> String {
+    format!("{:020}.{}", first_index, extension)
+}
+
+fn open_file(path: &Path, truncate: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(truncate)
+        .open(path)
+}
+
+/// Opens a segment, checks every record and brings its index file up to date
+fn load_segment(directory: &Path, first_index: u64, is_last: bool) -> Result<Segment, WalError> {
+    let log_path = directory.join(segment_file_name(first_index, SEGMENT_EXTENSION));
+    let index_path = directory.join(segment_file_name(first_index, INDEX_EXTENSION));
+    let mut log_file = open_file(&log_path, false)?;
+    let mut bytes = Vec::new();
+    log_file.read_to_end(&mut bytes)?;
+
+    let corrupted = |offset: usize, reason: String| WalError::Corrupted {
+        segment: log_path.clone(),
+        offset: offset as u64,
+        reason,
+    };
+
+    let mut slots = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let expected_index = first_index + (slots.len() as u64 / INDEX_SLOT_SIZE);
+        match decode_record(&bytes[offset..]) {
+            DecodedRecord::Valid { index, size, .. } => {
+                if index != expected_index {
+                    return Err(corrupted(offset, format!("expected index {}, found {}", expected_index, index)));
+                }
+                slots.extend_from_slice(&(offset as u64).to_be_bytes());
+                offset += size;
+            }
+            // A torn write can only affect the records written last, so a
+            // record running past the end of the segment hides no valid one
+            DecodedRecord::Incomplete if is_last && !valid_record_follows(&bytes, offset, expected_index) => break,
+            DecodedRecord::Invalid { size } if is_last && offset + size == bytes.len() => break,
+            DecodedRecord::Incomplete => return Err(corrupted(offset, "truncated record".to_string())),
+            DecodedRecord::Invalid { .. } => return Err(corrupted(offset, "checksum mismatch".to_string())),
+        }
+    }
+    if offset < bytes.len() {
+        log::warn!(
+            "Discarding {} bytes of torn record at the end of {}",
+            bytes.len() - offset,
+            log_path.display()
+        );
+        log_file.set_len(offset as u64)?;
+        log_file.sync_all()?;
+    }
+
+    let mut index_file = open_file(&index_path, false)?;
+    let mut stored_slots = Vec::new();
+    index_file.read_to_end(&mut stored_slots)?;
+    if stored_slots != slots {
+        log::info!("Rebuilding WAL index file {}", index_path.display());
+        index_file.set_len(0)?;
+        index_file.seek(SeekFrom::Start(0))?;
+        index_file.write_all(&slots)?;
+    }
+
+    Ok(Segment {
+        first_index,
+        entry_count: slots.len() as u64 / INDEX_SLOT_SIZE,
+        size: offset as u64,
+        log_path,
+        log: log_file,
+        index_path,
+        index: index_file,
+    })
+}
+
+fn encode_record(buffer: &mut Vec<u8>, entry: &LogEntry) {
+    let kind = [encode_kind(entry.kind)];
+    let length = (kind.len() + entry.len()) as u32;
+    buffer.extend_from_slice(&length.to_be_bytes());
+    buffer.extend_from_slice(&checksum(length, entry.term, entry.index, &[&kind, &entry.payload]).to_be_bytes());
+    buffer.extend_from_slice(&entry.term.to_be_bytes());
+    buffer.extend_from_slice(&entry.index.to_be_bytes());
+    buffer.extend_from_slice(&kind);
+    buffer.extend_from_slice(&entry.payload);
+}
+
+/// Computes the CRC of a record from its length, term, index and payload,
+/// given in parts
+fn checksum(length: u32, term: u64, index: u64, payload: &[&[u8]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&length.to_be_bytes());
+    hasher.update(&term.to_be_bytes());
+    hasher.update(&index.to_be_bytes());
+    for part in payload {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Whether a valid record of an entry after `index` starts anywhere past
+/// `offset`, which tells a corrupted record length from a torn write
+///
+/// Only the offsets where the header holds a plausible index are checksummed,
+/// given that every record between takes at least a header and a kind byte.
+fn valid_record_follows(bytes: &[u8], offset: usize, index: u64) -> bool {
+    (offset + 1..=bytes.len().saturating_sub(RECORD_HEADER_SIZE)).any(|start| {
+        let found = u64::from_be_bytes(bytes[start + 16..start + 24].try_into().unwrap());
+        let max_skipped = ((start - offset) / (RECORD_HEADER_SIZE + 1)) as u64;
+        found > index
+            && found - index <= max_skipped
+            && matches!(decode_record(&bytes[start..]), DecodedRecord::Valid { .. })
+    })
+}
+
+fn encode_kind(kind: EntryKind) -> u8 {
+    match kind {
+        EntryKind::Command => 0,
+        EntryKind::Configuration => 1,
+        EntryKind::Noop => 2,
+    }
+}
+
+/// Decodes the entry stored as a record payload, `None` if its kind is unknown
+fn decode_entry(term: u64, index: u64, payload: &[u8]) -> Option<LogEntry> {
+    let kind = match payload.first()? {
+        0 => EntryKind::Command,
+        1 => EntryKind::Configuration,
+        2 => EntryKind::Noop,
+        _ => return None,
+    };
+    Some(LogEntry::new(term, index, kind, payload[1..].to_vec()))
+}
+
+/// Outcome of decoding the record at the start of a byte slice
+enum DecodedRecord<'a> {
+    /// A complete record whose checksum matches; `size` includes the header
+    Valid {
+        term: u64,
+        index: u64,
+        payload: &'a [u8],
+        size: usize,
+    },
+    /// The slice ends before the end of the record
+    Incomplete,
+    /// A complete record whose checksum does not match
+    Invalid { size: usize },
+}
+
+fn decode_record(bytes: &[u8]) -> DecodedRecord<'_> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return DecodedRecord::Incomplete;
+    }
+    let length = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let size = RECORD_HEADER_SIZE + length;
+    if bytes.len() < size {
+        return DecodedRecord::Incomplete;
+    }
+
+    let crc = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+    let term = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+    let index = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
+    let payload = &bytes[RECORD_HEADER_SIZE..size];
+    if checksum(length as u32, term, index, &[payload]) != crc {
+        return DecodedRecord::Invalid { size };
+    }
+    DecodedRecord::Valid { term, index, payload, size }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_segments() -> WalOptions {
+        WalOptions {
+            max_segment_size: 3 * (RECORD_HEADER_SIZE as u64 + 3),
+            ..WalOptions::default()
+        }
+    }
+
+    fn command(term: u64, index: u64, bytes: &[u8]) -> LogEntry {
+        LogEntry::command(term, index, bytes.to_vec())
+    }
+
+    fn commands(term: u64, count: u8) -> Vec<LogEntry> {
+        (0..count).map(|value| command(term, value as u64 + 1, &[value, value])).collect()
+    }
+
+    fn segment_count(directory: &Path) -> usize {
+        fs::read_dir(directory)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == SEGMENT_EXTENSION)
+            .count()
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn entries_roll_over_into_new_segments() {
+        let directory = tempfile::tempdir().unwrap();
+        let entries: Vec<LogEntry> = (0..10)
+            .map(|value| command(if value < 4 { 1 } else { 2 }, value as u64 + 1, &[value, value]))
+            .collect();
+        {
+            let mut wal = Wal::open(directory.path(), small_segments()).unwrap();
+            wal.append(&entries[..4]).unwrap();
+            wal.append(&entries[4..]).unwrap();
+        }
+        assert_eq!(segment_count(directory.path()), 4);
+
+        // Index files are derived data and are rebuilt when missing
+        fs::remove_file(directory.path().join(segment_file_name(4, INDEX_EXTENSION))).unwrap();
+
+        let wal = Wal::open(directory.path(), small_segments()).unwrap();
+        assert_eq!((wal.first_index(), wal.last_index()), (1, 10));
+        let read = wal.read(3, 9).unwrap();
+        assert_eq!(read.len(), 6);
+        assert_eq!(read[0], command(1, 3, &[2, 2]));
+        assert_eq!(read[5], command(2, 8, &[7, 7]));
+    }
+
+    #[test]
+    fn truncation_removes_later_segments() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(directory.path(), small_segments()).unwrap();
+            wal.append(&commands(1, 8)).unwrap();
+            wal.truncate(3).unwrap();
+            wal.append(&[command(2, 3, &[9, 9])]).unwrap();
+        }
+        assert_eq!(segment_count(directory.path()), 1);
+
+        let wal = Wal::open(directory.path(), small_segments()).unwrap();
+        assert_eq!(wal.last_index(), 3);
+        assert_eq!(wal.read(2, 4).unwrap(), vec![command(1, 2, &[1, 1]), command(2, 3, &[9, 9])]);
+    }
+
+    #[test]
+    fn group_commit_syncs_once_enough_entries_are_waiting() {
+        let directory = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            durability: DurabilityPolicy::GroupCommit {
+                max_entries: 3,
+                max_delay: Duration::from_millis(5),
+            },
+            ..WalOptions::default()
+        };
+        let mut wal = Wal::open(directory.path(), options).unwrap();
+
+        wal.append(&[command(1, 1, &[1]), command(1, 2, &[2])]).unwrap();
+        assert_eq!(wal.durable_index(), 0);
+        assert!(wal.sync_deadline().is_some());
+
+        wal.append(&[command(1, 3, &[3])]).unwrap();
+        assert_eq!(wal.durable_index(), 3);
+        assert!(wal.sync_deadline().is_none());
+
+        wal.append(&[command(1, 4, &[4])]).unwrap();
+        wal.truncate(4).unwrap();
+        assert_eq!(wal.durable_index(), 3);
+        assert!(wal.sync_deadline().is_none());
+    }
+
+    #[test]
+    fn deferred_append_is_due_for_a_sync_at_once_with_every_append_durability() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+
+        wal.append_deferred(&commands(1, 2)).unwrap();
+        assert_eq!(wal.durable_index(), 0);
+        assert!(wal.sync_deadline().is_some_and(|deadline| deadline <= Instant::now()));
+
+        wal.sync().unwrap();
+        assert_eq!(wal.durable_index(), 2);
+        assert!(wal.sync_deadline().is_none());
+    }
+
+    #[test]
+    fn sync_runs_without_the_log_and_ignores_truncated_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+        wal.append_deferred(&commands(1, 3)).unwrap();
+
+        let sync = wal.start_sync().unwrap().unwrap();
+        assert!(wal.start_sync().unwrap().is_none());
+        assert!(wal.sync_deadline().is_none());
+        wal.truncate(3).unwrap();
+        wal.append_deferred(&[command(2, 3, &[3])]).unwrap();
+        sync.run().unwrap();
+        wal.finish_sync();
+
+        // The rewritten entry was not covered by the sync
+        assert_eq!(wal.durable_index(), 2);
+        assert!(wal.sync_deadline().is_some());
+        wal.start_sync().unwrap().unwrap().run().unwrap();
+        wal.finish_sync();
+        assert_eq!(wal.durable_index(), 3);
+        assert!(wal.sync_deadline().is_none());
+    }
+
+    #[test]
+    fn compaction_removes_covered_segments() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(directory.path(), small_segments()).unwrap();
+        wal.append(&commands(1, 8)).unwrap();
+
+        wal.compact(4).unwrap();
+        assert_eq!(segment_count(directory.path()), 2);
+        assert_eq!((wal.first_index(), wal.last_index()), (4, 8));
+        assert_eq!(wal.size(), 4 * (RECORD_HEADER_SIZE as u64 + 3));
+
+        // Compacting past the end continues the log after the compacted index
+        wal.compact(20).unwrap();
+        assert_eq!(segment_count(directory.path()), 0);
+        wal.append(&[command(2, 21, &[1])]).unwrap();
+        drop(wal);
+
+        let wal = Wal::open(directory.path(), small_segments()).unwrap();
+        assert_eq!((wal.first_index(), wal.last_index()), (21, 21));
+    }
+
+    #[test]
+    fn torn_record_at_the_tail_is_truncated() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+            wal.append(&commands(1, 3)).unwrap();
+        }
+        let segment_path = directory.path().join(segment_file_name(1, SEGMENT_EXTENSION));
+        let size = fs::metadata(&segment_path).unwrap().len();
+        flip_byte(&segment_path, size - 1);
+
+        let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+        assert_eq!(wal.last_index(), 2);
+        wal.append(&[command(2, 3, &[4])]).unwrap();
+        assert_eq!(wal.read(3, 4).unwrap(), vec![command(2, 3, &[4])]);
+    }
+
+    #[test]
+    fn torn_length_of_the_last_record_is_truncated() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+            wal.append(&commands(1, 3)).unwrap();
+        }
+        let segment_path = directory.path().join(segment_file_name(1, SEGMENT_EXTENSION));
+        flip_byte(&segment_path, 2 * (RECORD_HEADER_SIZE as u64 + 3));
+
+        let wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+        assert_eq!(wal.last_index(), 2);
+    }
+
+    #[test]
+    fn corrupted_length_in_the_last_segment_is_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+            wal.append(&commands(1, 3)).unwrap();
+        }
+        let segment_path = directory.path().join(segment_file_name(1, SEGMENT_EXTENSION));
+        let second_record = RECORD_HEADER_SIZE as u64 + 3;
+        // The length now runs past the end of the segment, like a torn write would
+        flip_byte(&segment_path, second_record);
+
+        match Wal::open(directory.path(), WalOptions::default()) {
+            Err(WalError::Corrupted { offset, .. }) => assert_eq!(offset, second_record),
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(wal) => panic!("corruption was not detected, log ends at {}", wal.last_index()),
+        }
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), 3 * second_record);
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(directory.path(), WalOptions::default()).unwrap();
+            wal.append(&commands(1, 3)).unwrap();
+        }
+        let segment_path = directory.path().join(segment_file_name(1, SEGMENT_EXTENSION));
+        let second_record = RECORD_HEADER_SIZE as u64 + 3;
+        flip_byte(&segment_path, second_record + RECORD_HEADER_SIZE as u64);
+
+        match Wal::open(directory.path(), WalOptions::default()) {
+            Err(WalError::Corrupted { segment, offset, .. }) => {
+                assert_eq!(segment, segment_path);
+                assert_eq!(offset, second_record);
+            }
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("corruption was not detected"),
+        }
+    }
+}